@@ -0,0 +1,365 @@
+use anyhow::Result;
+use burn::tensor::backend::Backend;
+use rand::{seq::SliceRandom, thread_rng};
+
+use crate::artifacts::ArtifactDir;
+use crate::data_structs::TrimmedTrack;
+use crate::inference::Predictor;
+use crate::labels::Guidance;
+use crate::thresholds::Thresholds;
+
+/// Order in which the labelling session presents tracks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum SamplingStrategy {
+    /// Shuffled, no model needed
+    #[default]
+    Random,
+    /// Smallest gap between the two most likely sublists first
+    Margin,
+    /// Highest entropy of the predicted probabilities first
+    Entropy,
+    /// Tracks least like the ones already picked first, in normalized feature space
+    Diversity,
+    /// Tracks likely to be in the sublists with the fewest labels first
+    Balance,
+}
+
+/// Difference between the two highest probabilities, small means the model can't decide.
+pub fn margin(probabilities: &[f32]) -> f32 {
+    let mut sorted = probabilities.to_vec();
+    sorted.sort_by(|a, b| b.total_cmp(a));
+    match sorted.as_slice() {
+        [first, second, ..] => first - second,
+        _ => 1.0,
+    }
+}
+
+pub fn entropy(probabilities: &[f32]) -> f32 {
+    probabilities
+        .iter()
+        .filter(|p| **p > 0.0)
+        .map(|p| -p * p.ln())
+        .sum()
+}
+
+/// Returns the indices of `candidates` in the order they should be labelled. `probabilities` and
+/// `features` are indexed like the motherlist, `label_counts` holds the number of labels each
+/// sublist already has.
+pub fn order(
+    strategy: SamplingStrategy,
+    candidates: &[usize],
+    probabilities: &[Vec<f32>],
+    features: &[Vec<f32>],
+    label_counts: &[usize],
+) -> Vec<usize> {
+    let mut order = candidates.to_vec();
+    match strategy {
+        SamplingStrategy::Random => order.shuffle(&mut thread_rng()),
+        SamplingStrategy::Margin => {
+            order.sort_by(|a, b| margin(&probabilities[*a]).total_cmp(&margin(&probabilities[*b])))
+        }
+        SamplingStrategy::Entropy => order
+            .sort_by(|a, b| entropy(&probabilities[*b]).total_cmp(&entropy(&probabilities[*a]))),
+        SamplingStrategy::Diversity => order = farthest_first(&order, probabilities, features),
+        SamplingStrategy::Balance => {
+            order = rarest_class_first(&order, probabilities, label_counts)
+        }
+    }
+    order
+}
+
+/// The model predictions that guide a labelling session, so tests can swap the model for a stub.
+pub trait Scorer {
+    fn class_names(&self) -> &[String];
+
+    /// The probability of every class for each track, see [`Predictor::predict`].
+    fn predict(&self, tracks: &[TrimmedTrack]) -> Vec<Vec<f32>>;
+
+    /// The classes a track is put in, see [`Predictor::guess`].
+    fn guess(&self, thresholds: &Thresholds, probabilities: &[f32]) -> Vec<usize>;
+}
+
+impl<B: Backend> Scorer for Predictor<B> {
+    fn class_names(&self) -> &[String] {
+        &self.class_names
+    }
+
+    fn predict(&self, tracks: &[TrimmedTrack]) -> Vec<Vec<f32>> {
+        Predictor::predict(self, tracks)
+    }
+
+    fn guess(&self, thresholds: &Thresholds, probabilities: &[f32]) -> Vec<usize> {
+        Predictor::guess(self, thresholds, probabilities)
+    }
+}
+
+/// Runs the model in `artifact_dir` over the motherlist to order the tracks without a label and
+/// guess the sublists of every track. `labels` are numbered from 1 like in
+/// [`crate::labels::get_labels`].
+pub fn guidance<B: Backend>(
+    artifact_dir: &ArtifactDir,
+    device: B::Device,
+    motherlist: &[TrimmedTrack],
+    labels: &[Vec<u32>],
+    strategy: SamplingStrategy,
+) -> Result<Guidance> {
+    let predictor = Predictor::<B>::load(artifact_dir, device)?;
+    let thresholds = Thresholds::load(artifact_dir)?;
+    let normalizer = artifact_dir.load_normalizer()?;
+    let features: Vec<Vec<f32>> = motherlist
+        .iter()
+        .map(|track| normalizer.transform(&track.feature_vector()))
+        .collect();
+    Ok(guide(
+        &predictor,
+        &thresholds,
+        &features,
+        motherlist,
+        labels,
+        strategy,
+    ))
+}
+
+/// Like [`guidance`] with a model already loaded, `features` are the normalized features of the
+/// motherlist.
+pub fn guide(
+    scorer: &impl Scorer,
+    thresholds: &Thresholds,
+    features: &[Vec<f32>],
+    motherlist: &[TrimmedTrack],
+    labels: &[Vec<u32>],
+    strategy: SamplingStrategy,
+) -> Guidance {
+    let probabilities = scorer.predict(motherlist);
+    let mut label_counts = vec![0; scorer.class_names().len()];
+    labels
+        .iter()
+        .flatten()
+        .for_each(|x| label_counts[*x as usize - 1] += 1);
+    let candidates: Vec<usize> = (0..motherlist.len())
+        .filter(|i| labels[*i].is_empty())
+        .collect();
+
+    Guidance {
+        order: order(
+            strategy,
+            &candidates,
+            &probabilities,
+            features,
+            &label_counts,
+        ),
+        guesses: probabilities
+            .iter()
+            .map(|p| {
+                scorer
+                    .guess(thresholds, p)
+                    .into_iter()
+                    .map(|class| class as u32 + 1)
+                    .collect()
+            })
+            .collect(),
+    }
+}
+
+// Greedy k-center: start from the most uncertain track, then keep picking the track farthest from
+// everything picked so far
+fn farthest_first(
+    candidates: &[usize],
+    probabilities: &[Vec<f32>],
+    features: &[Vec<f32>],
+) -> Vec<usize> {
+    let mut remaining = candidates.to_vec();
+    let Some(start) = remaining
+        .iter()
+        .enumerate()
+        .max_by(|a, b| entropy(&probabilities[*a.1]).total_cmp(&entropy(&probabilities[*b.1])))
+    else {
+        return vec![];
+    };
+    let mut order = vec![remaining.swap_remove(start.0)];
+    let mut distances: Vec<f32> = remaining
+        .iter()
+        .map(|x| squared_distance(&features[*x], &features[order[0]]))
+        .collect();
+
+    while let Some((next, _)) = distances
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+    {
+        let picked = remaining.swap_remove(next);
+        distances.swap_remove(next);
+        remaining
+            .iter()
+            .zip(distances.iter_mut())
+            .for_each(|(x, distance)| {
+                *distance = distance.min(squared_distance(&features[*x], &features[picked]))
+            });
+        order.push(picked);
+    }
+    order
+}
+
+// Takes turns between the sublists, fewest labels first, each time offering the remaining track
+// the model thinks most likely belongs to that sublist
+fn rarest_class_first(
+    candidates: &[usize],
+    probabilities: &[Vec<f32>],
+    label_counts: &[usize],
+) -> Vec<usize> {
+    if label_counts.is_empty() {
+        return candidates.to_vec();
+    }
+    let mut classes: Vec<usize> = (0..label_counts.len()).collect();
+    classes.sort_by_key(|class| label_counts[*class]);
+    let mut remaining = candidates.to_vec();
+    let mut order = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        for class in classes.iter() {
+            let Some((next, _)) = remaining
+                .iter()
+                .enumerate()
+                .max_by(|a, b| probabilities[*a.1][*class].total_cmp(&probabilities[*b.1][*class]))
+            else {
+                break;
+            };
+            order.push(remaining.swap_remove(next));
+        }
+    }
+    order
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y).powi(2)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What a model would predict for four tracks and two sublists: sure, unsure, in between and
+    // leaning towards the second sublist
+    fn probabilities() -> Vec<Vec<f32>> {
+        vec![
+            vec![0.9, 0.1],
+            vec![0.5, 0.5],
+            vec![0.7, 0.3],
+            vec![0.2, 0.8],
+        ]
+    }
+
+    // Predicts `probabilities()` for the tracks by position in the motherlist, named by index
+    struct StubScorer {
+        class_names: Vec<String>,
+    }
+
+    impl Scorer for StubScorer {
+        fn class_names(&self) -> &[String] {
+            &self.class_names
+        }
+
+        fn predict(&self, tracks: &[TrimmedTrack]) -> Vec<Vec<f32>> {
+            let probabilities = probabilities();
+            tracks
+                .iter()
+                .map(|x| probabilities[x.track_id.parse::<usize>().unwrap()].clone())
+                .collect()
+        }
+
+        fn guess(&self, _: &Thresholds, probabilities: &[f32]) -> Vec<usize> {
+            (0..probabilities.len())
+                .filter(|x| probabilities[*x] > 0.6)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn guide_orders_by_the_model_uncertainty() {
+        let scorer = StubScorer {
+            class_names: vec!["Rock".to_string(), "Jazz".to_string()],
+        };
+        // Shuffled so the order can only come from the model
+        let motherlist: Vec<TrimmedTrack> = ["2", "0", "3", "1"]
+            .iter()
+            .map(|x| TrimmedTrack::fake(x, x, &["A"], 0))
+            .collect();
+        let labels = vec![vec![], vec![], vec![2], vec![]];
+        let guidance = guide(
+            &scorer,
+            &Thresholds::default(),
+            &[],
+            &motherlist,
+            &labels,
+            SamplingStrategy::Margin,
+        );
+        // Unsure "1", in between "2", then sure "0", the labelled "3" left out
+        assert_eq!(guidance.order, vec![3, 0, 1]);
+        assert_eq!(guidance.guesses, vec![vec![1], vec![1], vec![2], vec![]]);
+    }
+
+    #[test]
+    fn margin_is_the_gap_between_the_top_two() {
+        assert!((margin(&[0.2, 0.5, 0.3]) - 0.2).abs() < 1e-6);
+        assert_eq!(margin(&[1.0]), 1.0);
+    }
+
+    #[test]
+    fn entropy_is_highest_for_uniform_probabilities() {
+        assert!((entropy(&[0.25; 4]) - 4f32.ln()).abs() < 1e-6);
+        assert_eq!(entropy(&[1.0, 0.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn uncertainty_strategies_put_the_least_sure_tracks_first() {
+        let probabilities = probabilities();
+        for strategy in [SamplingStrategy::Margin, SamplingStrategy::Entropy] {
+            assert_eq!(
+                order(strategy, &[0, 1, 2, 3], &probabilities, &[], &[]),
+                vec![1, 2, 3, 0]
+            );
+            // Labelled tracks aren't candidates and never show up
+            assert_eq!(
+                order(strategy, &[0, 2], &probabilities, &[], &[]),
+                vec![2, 0]
+            );
+        }
+    }
+
+    #[test]
+    fn random_order_keeps_every_candidate() {
+        let mut shuffled = order(
+            SamplingStrategy::Random,
+            &[0, 1, 2, 3],
+            &probabilities(),
+            &[],
+            &[],
+        );
+        shuffled.sort();
+        assert_eq!(shuffled, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn farthest_first_spreads_out_over_feature_space() {
+        // Starts from the most uncertain track, then the one farthest from everything picked
+        let probabilities = vec![
+            vec![0.5, 0.5],
+            vec![0.9, 0.1],
+            vec![0.9, 0.1],
+            vec![0.9, 0.1],
+        ];
+        let features = vec![vec![0.0], vec![1.0], vec![10.0], vec![5.0]];
+        assert_eq!(
+            farthest_first(&[0, 1, 2, 3], &probabilities, &features),
+            vec![0, 2, 3, 1]
+        );
+    }
+
+    #[test]
+    fn rarest_class_first_takes_turns_from_the_emptiest_sublist() {
+        // The second sublist has no labels yet, so its likeliest track comes first
+        assert_eq!(
+            rarest_class_first(&[0, 1, 2, 3], &probabilities(), &[5, 0]),
+            vec![3, 0, 1, 2]
+        );
+    }
+}
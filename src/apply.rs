@@ -0,0 +1,566 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::client::{PlaylistClient, PlaylistSummary, MAX_ITEMS_PER_REQUEST};
+use crate::hierarchy::Hierarchy;
+use crate::label_store::{LabelSource, LabelStore};
+use crate::misc_helpers::OutputFormat;
+use crate::thresholds::UNSORTED;
+
+/// Description given to the playlists this tool creates, so users know not to be surprised when
+/// their contents change.
+pub const MANAGED_DESCRIPTION: &str =
+    "Managed by SpotifyPlaylists, tracks are added and removed to match its sublist. Songs you add or remove by hand are kept that way.";
+
+pub const APPLIED_FILE: &str = "data/applied.json";
+
+/// Contents of every playlist as of the last apply, what manual edits are detected against.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AppliedState {
+    pub playlists: BTreeMap<String, AppliedPlaylist>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppliedPlaylist {
+    pub playlist_id: String,
+    pub track_ids: BTreeSet<String>,
+}
+
+impl AppliedState {
+    /// Loads the last applied state, empty if nothing has been applied yet.
+    pub fn load() -> Result<Self> {
+        if !Path::new(APPLIED_FILE).exists() {
+            return Ok(Self::default());
+        }
+        let file = File::open(APPLIED_FILE).context("Error in opening applied state")?;
+        serde_json::from_reader(BufReader::new(file)).context("Error in reading applied state")
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let file = File::create(APPLIED_FILE).context("Error in creating applied state")?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)
+            .context("Error in writing applied state")
+    }
+}
+
+/// A track the user added to or removed from a managed playlist in Spotify since the last apply.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManualEdit {
+    pub track_id: String,
+    pub sublist: String,
+    pub added: bool,
+}
+
+/// Compares every playlist to its contents at the last apply. The Unsorted playlist is left out
+/// since being put there by hand says nothing about where a track belongs.
+pub async fn detect_manual_edits<C: PlaylistClient>(
+    client: &C,
+    applied: &AppliedState,
+) -> Result<Vec<ManualEdit>> {
+    let playlists = client.user_playlists().await?;
+    let mut edits = Vec::new();
+    for (sublist, last) in applied.playlists.iter() {
+        // A deleted playlist is recreated rather than read as every track being removed
+        if sublist == UNSORTED || !playlists.iter().any(|x| x.id == last.playlist_id) {
+            continue;
+        }
+        let current: BTreeSet<String> = client
+            .playlist_track_ids(&last.playlist_id)
+            .await?
+            .into_iter()
+            .collect();
+        let edit = |track_id: &String, added: bool| ManualEdit {
+            track_id: track_id.clone(),
+            sublist: sublist.clone(),
+            added,
+        };
+        edits.extend(current.difference(&last.track_ids).map(|x| edit(x, true)));
+        edits.extend(last.track_ids.difference(&current).map(|x| edit(x, false)));
+    }
+    Ok(edits)
+}
+
+/// Turns manual edits into manual labels, so the next plan keeps them as they are.
+pub fn record_manual_edits(store: &mut LabelStore, edits: &[ManualEdit]) {
+    edits
+        .iter()
+        .for_each(|x| store.record_manual_edit(&x.track_id, &x.sublist, x.added));
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ApplyOptions {
+    /// Create missing playlists as public instead of private.
+    pub public: bool,
+    /// Also keep an Unsorted playlist with the tracks no sublist was confident enough about.
+    pub include_unsorted: bool,
+    /// Put the tracks of nested sublists in the playlists of every sublist above them too.
+    pub include_descendants: bool,
+}
+
+/// The changes needed to make the playlist of one sublist match its assignments.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlaylistDiff {
+    pub sublist: String,
+    /// `None` when the playlist doesn't exist yet and will be created.
+    pub playlist_id: Option<String>,
+    pub add: Vec<String>,
+    pub remove: Vec<String>,
+    pub keep: Vec<String>,
+}
+
+impl PlaylistDiff {
+    pub fn change_count(&self) -> usize {
+        self.add.len() + self.remove.len()
+    }
+}
+
+/// Works out, without modifying anything, what [`execute`] has to do so the playlist of every
+/// sublist contains exactly the tracks assigned to it in the label store. `smart` holds the
+/// members of the sublists defined by rules, see [`crate::rules`]. Only playlists this tool
+/// created are touched, see [`managed_playlist`].
+pub async fn plan<C: PlaylistClient>(
+    client: &C,
+    applied: &AppliedState,
+    sublists: &[String],
+    hierarchy: &Hierarchy,
+    store: &LabelStore,
+    smart: &BTreeMap<String, BTreeSet<String>>,
+    options: ApplyOptions,
+) -> Result<Vec<PlaylistDiff>> {
+    let playlists = client.user_playlists().await?;
+    let mut assignments = store.assignments();
+    assignments.extend(smart.iter().map(|(name, x)| (name.clone(), x.clone())));
+    let mut names: Vec<String> = sublists.to_vec();
+    names.extend(smart.keys().cloned());
+    if options.include_unsorted {
+        names.push(UNSORTED.to_string());
+    }
+
+    let mut diffs = Vec::with_capacity(names.len());
+    for name in names {
+        let playlist_id = managed_playlist(client, &playlists, applied, &name).await?;
+        let current: BTreeSet<String> = match &playlist_id {
+            Some(id) => client.playlist_track_ids(id).await?.into_iter().collect(),
+            None => BTreeSet::new(),
+        };
+        let mut target = assignments.get(&name).cloned().unwrap_or_default();
+        if options.include_descendants {
+            // Tracks taken out of the parent playlist by hand stay out of it
+            let excluded = |track_id: &String| {
+                store
+                    .get(track_id, LabelSource::Manual)
+                    .is_some_and(|x| x.excluded.contains(&name))
+            };
+            hierarchy
+                .descendants(&name)
+                .into_iter()
+                .filter_map(|x| assignments.get(x))
+                .flatten()
+                .filter(|x| !excluded(x))
+                .for_each(|x| {
+                    target.insert(x.clone());
+                });
+        }
+        diffs.push(PlaylistDiff {
+            add: target.difference(&current).cloned().collect(),
+            remove: current.difference(&target).cloned().collect(),
+            keep: target.intersection(&current).cloned().collect(),
+            sublist: name,
+            playlist_id,
+        });
+    }
+    Ok(diffs)
+}
+
+/// The playlist of a sublist, if there is one this tool created: the one it was applied to last
+/// time, or, when that record is lost, one of the user's own playlists with the sublist's name
+/// and [`MANAGED_DESCRIPTION`]. Any other playlist with that name belongs to the user, so it is an
+/// error rather than something to take over and empty.
+async fn managed_playlist<C: PlaylistClient>(
+    client: &C,
+    playlists: &[PlaylistSummary],
+    applied: &AppliedState,
+    name: &str,
+) -> Result<Option<String>> {
+    if let Some(last) = applied.playlists.get(name) {
+        if playlists.iter().any(|x| x.id == last.playlist_id) {
+            return Ok(Some(last.playlist_id.clone()));
+        }
+    }
+    let mut managed = None;
+    for playlist in playlists.iter().filter(|x| x.name == name) {
+        if !playlist.owned
+            || client.playlist_description(&playlist.id).await? != MANAGED_DESCRIPTION
+        {
+            bail!(
+                "There already is a playlist named {name:?} that wasn't created by this tool, \
+                 rename it or the sublist so it isn't overwritten"
+            );
+        }
+        managed.get_or_insert(playlist.id.clone());
+    }
+    Ok(managed)
+}
+
+/// Refuses a plan that would add or remove more than `max_changes` tracks in total.
+pub fn check_change_limit(diffs: &[PlaylistDiff], max_changes: usize) -> Result<()> {
+    let changes: usize = diffs.iter().map(PlaylistDiff::change_count).sum();
+    if changes > max_changes {
+        bail!(
+            "{changes} changes exceed the safety limit of {max_changes}, raise --max-changes to \
+             go ahead"
+        );
+    }
+    Ok(())
+}
+
+/// Carries out a plan from [`plan`], creating missing playlists and adding and removing tracks
+/// in batches of [`MAX_ITEMS_PER_REQUEST`]. `applied` follows every request, so if one fails it
+/// still holds what the playlists were left in and the tracks this tool added or removed aren't
+/// read as manual edits next time. Playlists no longer in the plan are dropped once it is done.
+pub async fn execute<C: PlaylistClient>(
+    client: &C,
+    applied: &mut AppliedState,
+    diffs: &[PlaylistDiff],
+    options: ApplyOptions,
+) -> Result<()> {
+    for diff in diffs {
+        let name = &diff.sublist;
+        let playlist_id = match &diff.playlist_id {
+            Some(id) => id.clone(),
+            None => {
+                client
+                    .create_playlist(name, MANAGED_DESCRIPTION, options.public)
+                    .await?
+            }
+        };
+        let mut record = |track_ids: &BTreeSet<String>| {
+            applied.playlists.insert(
+                name.clone(),
+                AppliedPlaylist {
+                    playlist_id: playlist_id.clone(),
+                    track_ids: track_ids.clone(),
+                },
+            );
+        };
+
+        // What the playlist holds right now
+        let mut track_ids: BTreeSet<String> = diff
+            .keep
+            .iter()
+            .chain(diff.remove.iter())
+            .cloned()
+            .collect();
+        record(&track_ids);
+        for chunk in diff.add.chunks(MAX_ITEMS_PER_REQUEST) {
+            client
+                .add_tracks(&playlist_id, chunk)
+                .await
+                .with_context(|| format!("Error in adding tracks to {name}"))?;
+            track_ids.extend(chunk.iter().cloned());
+            record(&track_ids);
+        }
+        for chunk in diff.remove.chunks(MAX_ITEMS_PER_REQUEST) {
+            client
+                .remove_tracks(&playlist_id, chunk)
+                .await
+                .with_context(|| format!("Error in removing tracks from {name}"))?;
+            track_ids.retain(|x| !chunk.contains(x));
+            record(&track_ids);
+        }
+    }
+    applied
+        .playlists
+        .retain(|name, _| diffs.iter().any(|x| x.sublist == *name));
+    Ok(())
+}
+
+/// Prints the plan, `track_names` turns track ids into something readable in the table format.
+pub fn print_diff(
+    diffs: &[PlaylistDiff],
+    track_names: &HashMap<String, String>,
+    format: OutputFormat,
+) -> Result<()> {
+    match format {
+        OutputFormat::Json => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(diffs).context("Error in serializing diff")?
+            );
+        }
+        OutputFormat::Table => {
+            let name = |id: &String| track_names.get(id).unwrap_or(id).clone();
+            diffs.iter().for_each(|diff| {
+                println!(
+                    "{}{}: +{} -{} ={}",
+                    diff.sublist,
+                    if diff.playlist_id.is_none() {
+                        " (new playlist)"
+                    } else {
+                        ""
+                    },
+                    diff.add.len(),
+                    diff.remove.len(),
+                    diff.keep.len()
+                );
+                diff.add.iter().for_each(|x| println!("  + {}", name(x)));
+                diff.remove.iter().for_each(|x| println!("  - {}", name(x)));
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::fake::FakeClient;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn store(labels: &[(&str, Vec<&str>)]) -> LabelStore {
+        let mut store = LabelStore::default();
+        labels
+            .iter()
+            .for_each(|(track_id, sublists)| store.set_manual(track_id, names(sublists)));
+        store
+    }
+
+    async fn plan_flat(
+        client: &FakeClient,
+        applied: &AppliedState,
+        sublists: &[&str],
+        store: &LabelStore,
+    ) -> Result<Vec<PlaylistDiff>> {
+        plan(
+            client,
+            applied,
+            &names(sublists),
+            &Hierarchy::default(),
+            store,
+            &BTreeMap::new(),
+            ApplyOptions::default(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn missing_playlists_are_created_with_their_tracks() {
+        let client = FakeClient::default();
+        let store = store(&[("t1", vec!["A"]), ("t2", vec!["A", "B"])]);
+        let diffs = plan_flat(&client, &AppliedState::default(), &["A", "B"], &store)
+            .await
+            .unwrap();
+        assert!(diffs.iter().all(|x| x.playlist_id.is_none()));
+        assert_eq!(diffs[0].add, names(&["t1", "t2"]));
+        assert_eq!(diffs[1].add, names(&["t2"]));
+
+        let mut applied = AppliedState::default();
+        execute(&client, &mut applied, &diffs, ApplyOptions::default())
+            .await
+            .unwrap();
+        let playlists = client.playlists.borrow();
+        assert_eq!(playlists.len(), 2);
+        assert!(playlists
+            .iter()
+            .all(|x| x.description == MANAGED_DESCRIPTION));
+        assert_eq!(playlists[0].track_ids, names(&["t1", "t2"]));
+        assert_eq!(applied.playlists["B"].playlist_id, playlists[1].id);
+        assert_eq!(
+            applied.playlists["B"].track_ids,
+            BTreeSet::from(["t2".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn managed_playlists_get_tracks_added_and_removed() {
+        let client = FakeClient::default();
+        let id = client.add_playlist("A", MANAGED_DESCRIPTION, true, &["t1", "t3"]);
+        let store = store(&[("t1", vec!["A"]), ("t2", vec!["A"])]);
+        let diffs = plan_flat(&client, &AppliedState::default(), &["A"], &store)
+            .await
+            .unwrap();
+        assert_eq!(diffs[0].playlist_id.as_deref(), Some(id.as_str()));
+        assert_eq!(diffs[0].add, names(&["t2"]));
+        assert_eq!(diffs[0].remove, names(&["t3"]));
+        assert_eq!(diffs[0].keep, names(&["t1"]));
+
+        execute(
+            &client,
+            &mut AppliedState::default(),
+            &diffs,
+            ApplyOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(client.track_ids(&id), names(&["t1", "t2"]));
+    }
+
+    #[tokio::test]
+    async fn playlists_of_the_user_with_a_sublist_name_are_refused() {
+        let store = store(&[("t1", vec!["A"])]);
+
+        let client = FakeClient::default();
+        client.add_playlist("A", "My favourites", true, &["t9"]);
+        assert!(plan_flat(&client, &AppliedState::default(), &["A"], &store)
+            .await
+            .is_err());
+
+        // Someone else's playlist can't have been created by this tool whatever it says
+        let client = FakeClient::default();
+        client.add_playlist("A", MANAGED_DESCRIPTION, false, &["t9"]);
+        assert!(plan_flat(&client, &AppliedState::default(), &["A"], &store)
+            .await
+            .is_err());
+        assert_eq!(client.track_ids("playlist0"), names(&["t9"]));
+    }
+
+    #[tokio::test]
+    async fn the_applied_playlist_is_used_even_if_renamed() {
+        let client = FakeClient::default();
+        let id = client.add_playlist("A (old)", MANAGED_DESCRIPTION, true, &["t1"]);
+        let mut applied = AppliedState::default();
+        applied.playlists.insert(
+            "A".to_string(),
+            AppliedPlaylist {
+                playlist_id: id.clone(),
+                track_ids: BTreeSet::from(["t1".to_string()]),
+            },
+        );
+        let diffs = plan_flat(&client, &applied, &["A"], &store(&[("t1", vec!["A"])]))
+            .await
+            .unwrap();
+        assert_eq!(diffs[0].playlist_id, Some(id));
+        assert_eq!(diffs[0].change_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn tracks_are_sent_in_batches() {
+        let client = FakeClient::default();
+        let track_ids: Vec<String> = (0..2 * MAX_ITEMS_PER_REQUEST + 50)
+            .map(|i| format!("t{i}"))
+            .collect();
+        let diffs = vec![PlaylistDiff {
+            sublist: "A".to_string(),
+            playlist_id: None,
+            add: track_ids.clone(),
+            remove: vec![],
+            keep: vec![],
+        }];
+        execute(
+            &client,
+            &mut AppliedState::default(),
+            &diffs,
+            ApplyOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            *client.requests.borrow(),
+            vec![MAX_ITEMS_PER_REQUEST, MAX_ITEMS_PER_REQUEST, 50]
+        );
+        assert_eq!(client.track_ids("playlist0"), track_ids);
+    }
+
+    #[tokio::test]
+    async fn a_failed_request_keeps_what_was_applied_before_it() {
+        let client = FakeClient {
+            fail_after: Some(2),
+            ..Default::default()
+        };
+        let id = client.add_playlist("A", MANAGED_DESCRIPTION, true, &["t0", "t1"]);
+        let added: Vec<String> = (2..MAX_ITEMS_PER_REQUEST + 2)
+            .map(|i| format!("t{i}"))
+            .collect();
+        let diffs = vec![
+            PlaylistDiff {
+                sublist: "A".to_string(),
+                playlist_id: Some(id.clone()),
+                add: added.clone(),
+                remove: names(&["t1"]),
+                keep: names(&["t0"]),
+            },
+            PlaylistDiff {
+                sublist: "B".to_string(),
+                playlist_id: None,
+                add: names(&["t0"]),
+                remove: vec![],
+                keep: vec![],
+            },
+        ];
+        let mut applied = AppliedState::default();
+        applied.playlists.insert(
+            "Old".to_string(),
+            AppliedPlaylist {
+                playlist_id: "playlist9".to_string(),
+                track_ids: BTreeSet::new(),
+            },
+        );
+
+        assert!(
+            execute(&client, &mut applied, &diffs, ApplyOptions::default())
+                .await
+                .is_err()
+        );
+        // The additions and the removal went through, the new playlist was never filled
+        let a: BTreeSet<String> = names(&["t0"]).into_iter().chain(added).collect();
+        assert_eq!(applied.playlists["A"].track_ids, a);
+        assert!(applied.playlists["B"].track_ids.is_empty());
+        assert_eq!(
+            client.track_ids(&id).into_iter().collect::<BTreeSet<_>>(),
+            a
+        );
+        // Nothing is dropped from a plan that didn't finish
+        assert!(applied.playlists.contains_key("Old"));
+    }
+
+    #[test]
+    fn plans_over_the_change_limit_are_refused() {
+        let diffs = vec![PlaylistDiff {
+            sublist: "A".to_string(),
+            playlist_id: None,
+            add: names(&["t1", "t2"]),
+            remove: names(&["t3"]),
+            keep: names(&["t4"]),
+        }];
+        assert!(check_change_limit(&diffs, 2).is_err());
+        assert!(check_change_limit(&diffs, 3).is_ok());
+    }
+
+    #[tokio::test]
+    async fn hand_edits_become_manual_labels() {
+        let client = FakeClient::default();
+        // t3 was added by hand and t2 taken out since the last apply
+        let id = client.add_playlist("A", MANAGED_DESCRIPTION, true, &["t1", "t3"]);
+        let mut applied = AppliedState::default();
+        applied.playlists.insert(
+            "A".to_string(),
+            AppliedPlaylist {
+                playlist_id: id,
+                track_ids: BTreeSet::from(["t1".to_string(), "t2".to_string()]),
+            },
+        );
+        let mut store = store(&[("t1", vec!["A"]), ("t2", vec!["A", "B"])]);
+
+        let edits = detect_manual_edits(&client, &applied).await.unwrap();
+        assert_eq!(edits.len(), 2);
+        record_manual_edits(&mut store, &edits);
+
+        let t2 = store.get("t2", LabelSource::Manual).unwrap();
+        assert_eq!(t2.sublists, names(&["B"]));
+        assert_eq!(t2.excluded, names(&["A"]));
+        let t3 = store.get("t3", LabelSource::Manual).unwrap();
+        assert_eq!(t3.sublists, names(&["A"]));
+
+        // The next plan leaves the playlist as the user edited it
+        let diffs = plan_flat(&client, &applied, &["A"], &store).await.unwrap();
+        assert_eq!(diffs[0].change_count(), 0);
+    }
+}
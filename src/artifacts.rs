@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use burn::config::Config;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::hierarchy::Hierarchy;
+use crate::normalizer::Normalizer;
+use crate::training::{TrainingConfig, ValidationReport};
+
+/// Directory holding everything a training run produces, so inference can be done from it alone.
+///
+/// ```text
+/// <artifact_dir>/
+///     config.json       training config, including the model config
+///     model.mpk.gz      trained model record
+///     normalizer.json   feature statistics the model was trained with
+///     class_names.json  sublist names in class id order
+///     hierarchy.json    nesting of the sublists, absent when they are flat
+///     metrics.json      final validation metrics
+///     validation.json   ids of the tracks held out for validation
+///     checkpoint/       model, optimizer and scheduler state every few epochs
+///     train/, valid/    per-epoch metric logs written by the learner
+/// ```
+#[derive(Debug, Clone)]
+pub struct ArtifactDir {
+    path: PathBuf,
+}
+
+impl ArtifactDir {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn create(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.path).context("Error in creating artifact directory")
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A nested artifact directory, used for runs that belong to this one like ablations.
+    pub fn child(&self, name: &str) -> Self {
+        Self::new(self.path.join(name))
+    }
+
+    /// Path of the model record, without the extension the recorder adds.
+    pub fn model_path(&self) -> PathBuf {
+        self.path.join("model")
+    }
+
+    pub fn save_config(&self, config: &TrainingConfig) -> Result<()> {
+        config
+            .save(self.path.join("config.json"))
+            .context("Error in saving training config")
+    }
+
+    pub fn load_config(&self) -> Result<TrainingConfig> {
+        TrainingConfig::load(self.path.join("config.json"))
+            .context("Error in loading training config")
+    }
+
+    pub fn save_normalizer(&self, normalizer: &Normalizer) -> Result<()> {
+        normalizer.save(&self.path.join("normalizer.json"))
+    }
+
+    pub fn load_normalizer(&self) -> Result<Normalizer> {
+        Normalizer::load(&self.path.join("normalizer.json"))
+    }
+
+    pub fn save_class_names(&self, class_names: &[String]) -> Result<()> {
+        write_json(&self.path.join("class_names.json"), &class_names)
+    }
+
+    pub fn load_class_names(&self) -> Result<Vec<String>> {
+        read_json(&self.path.join("class_names.json"))
+    }
+
+    pub fn save_hierarchy(&self, hierarchy: &Hierarchy) -> Result<()> {
+        hierarchy.save_to(&self.path.join("hierarchy.json"))
+    }
+
+    /// Runs trained before sublists could be nested load as flat.
+    pub fn load_hierarchy(&self) -> Result<Hierarchy> {
+        Hierarchy::load_from(&self.path.join("hierarchy.json"))
+    }
+
+    pub fn save_metrics(&self, report: &ValidationReport) -> Result<()> {
+        write_json(&self.path.join("metrics.json"), report)
+    }
+
+    /// Saves which tracks were held out, so thresholds are tuned on tracks the model never saw even
+    /// after labels were added.
+    pub fn save_validation_tracks(&self, track_ids: &[String]) -> Result<()> {
+        write_json(&self.path.join("validation.json"), track_ids)
+    }
+
+    pub fn load_validation_tracks(&self) -> Result<Vec<String>> {
+        read_json(&self.path.join("validation.json"))
+    }
+
+    /// Epoch of the most recent checkpoint written by the learner, if there is one.
+    pub fn latest_checkpoint(&self) -> Option<usize> {
+        std::fs::read_dir(self.path.join("checkpoint"))
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                // Checkpoints are named like "model-12.mpk.gz"
+                let name = entry.file_name().into_string().ok()?;
+                let epoch = name.strip_prefix("model-")?.split('.').next()?;
+                epoch.parse().ok()
+            })
+            .max()
+    }
+}
+
+fn write_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Error in creating {path:?}"))?;
+    serde_json::to_writer_pretty(BufWriter::new(file), value)
+        .with_context(|| format!("Error in writing {path:?}"))
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let file = File::open(path).with_context(|| format!("Error in opening {path:?}"))?;
+    serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("Error in reading {path:?}"))
+}
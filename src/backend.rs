@@ -0,0 +1,39 @@
+#[cfg(not(any(
+    feature = "backend-ndarray",
+    feature = "backend-wgpu",
+    feature = "backend-cuda",
+    feature = "backend-tch"
+)))]
+compile_error!("At least one of the backend-* features has to be enabled");
+
+/// Backends compiled into this build, chosen at runtime with `--backend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BackendKind {
+    /// CPU backend, always works but is the slowest
+    #[cfg(feature = "backend-ndarray")]
+    Ndarray,
+    /// GPU backend through Vulkan, Metal or DirectX
+    #[cfg(feature = "backend-wgpu")]
+    Wgpu,
+    /// NVIDIA GPU backend through libtorch
+    #[cfg(feature = "backend-cuda")]
+    Cuda,
+    /// CPU backend through libtorch
+    #[cfg(feature = "backend-tch")]
+    Tch,
+}
+
+impl Default for BackendKind {
+    // Prefer the CPU backend since it runs everywhere
+    #[allow(unreachable_code)]
+    fn default() -> Self {
+        #[cfg(feature = "backend-ndarray")]
+        return BackendKind::Ndarray;
+        #[cfg(feature = "backend-tch")]
+        return BackendKind::Tch;
+        #[cfg(feature = "backend-wgpu")]
+        return BackendKind::Wgpu;
+        #[cfg(feature = "backend-cuda")]
+        return BackendKind::Cuda;
+    }
+}
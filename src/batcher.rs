@@ -1,39 +1,476 @@
 use super::tokenizer::Tokenizer;
+use crate::data_structs::{TrimmedTrack, FEATURE_COUNT, SEGMENT_FEATURE_COUNT};
+use crate::dataset::TrackClassificationItem;
+use crate::normalizer::Normalizer;
 use burn::{
     data::dataloader::batcher::Batcher,
     nn::attention::generate_padding_mask,
-    tensor::{backend::Backend, Bool, Data, ElementConversion, Int, Tensor},
+    tensor::{backend::Backend, Bool, Data, ElementConversion, Int, Shape, Tensor},
 };
 use derive_new::new;
 use std::sync::Arc;
 
+// Stacks every label of each item into a [batch, n_classes] tensor of 0s and 1s
+fn multi_hot<B: Backend>(
+    items: &[TrackClassificationItem],
+    n_classes: usize,
+    device: &B::Device,
+) -> Tensor<B, 2> {
+    let mut values = vec![0.0f32; items.len() * n_classes];
+    items.iter().enumerate().for_each(|(row, item)| {
+        item.labels
+            .iter()
+            .for_each(|class| values[row * n_classes + class] = 1.0)
+    });
+    let data = Data::new(values, Shape::new([items.len(), n_classes]));
+    Tensor::from_data(data.convert()).to_device(device)
+}
+
 pub struct SongClassificationBatcher<B: Backend> {
     tokenizer: Arc<dyn Tokenizer>,
+    normalizer: Arc<Normalizer>,
     device: B::Device,
     max_seq_length: usize,
+    n_classes: usize,
 }
 
 impl<B: Backend> SongClassificationBatcher<B> {
-    pub fn new(tokenizer: Arc<dyn Tokenizer>, device: B::Device, max_seq_length: usize) -> Self {
+    pub fn new(
+        tokenizer: Arc<dyn Tokenizer>,
+        normalizer: Arc<Normalizer>,
+        device: B::Device,
+        max_seq_length: usize,
+        n_classes: usize,
+    ) -> Self {
         Self {
             tokenizer,
+            normalizer,
             device,
             max_seq_length,
+            n_classes,
         }
     }
+
+    // Tokenizes the track descriptions and pads them to a common length
+    fn tokenize(&self, tracks: &[&TrimmedTrack]) -> (Tensor<B, 2, Int>, Tensor<B, 2, Bool>) {
+        let tokens_list = tracks
+            .iter()
+            .map(|track| self.tokenizer.encode(&track.text_description()))
+            .collect();
+        let mask = generate_padding_mask(
+            self.tokenizer.pad_token(),
+            tokens_list,
+            Some(self.max_seq_length),
+            &B::Device::default(),
+        );
+        (
+            mask.tensor.to_device(&self.device),
+            mask.mask.to_device(&self.device),
+        )
+    }
+
+    // Stacks the normalized feature vectors into a [batch, FEATURE_COUNT] tensor
+    fn features(&self, tracks: &[&TrimmedTrack]) -> Tensor<B, 2> {
+        let values: Vec<f32> = tracks
+            .iter()
+            .flat_map(|track| self.normalizer.transform(&track.feature_vector()))
+            .collect();
+        let data = Data::new(values, Shape::new([tracks.len(), FEATURE_COUNT]));
+        Tensor::from_data(data.convert()).to_device(&self.device)
+    }
 }
 
 /// Struct for training batch
 #[derive(Debug, Clone, new)]
 pub struct TextClassificationTrainingBatch<B: Backend> {
     pub tokens: Tensor<B, 2, Int>,    // Tokenized text
+    pub features: Tensor<B, 2>,       // Normalized numeric features
     pub labels: Tensor<B, 1, Int>,    // Labels of the text
+    pub label_sets: Tensor<B, 2>,     // Every label of the text as a multi-hot vector
     pub mask_pad: Tensor<B, 2, Bool>, // Padding mask for the tokenized text
 }
 
+/// Training batches that know every label of their tracks, for scoring multi-label models.
+pub trait LabelSets<B: Backend> {
+    /// Every label of each track as a [batch, n_classes] multi-hot tensor.
+    fn label_sets(&self) -> Tensor<B, 2>;
+}
+
+impl<B: Backend> LabelSets<B> for TextClassificationTrainingBatch<B> {
+    fn label_sets(&self) -> Tensor<B, 2> {
+        self.label_sets.clone()
+    }
+}
+
 /// Struct for inference batch
 #[derive(Debug, Clone, new)]
 pub struct TextClassificationInferenceBatch<B: Backend> {
     pub tokens: Tensor<B, 2, Int>,    // Tokenized text
+    pub features: Tensor<B, 2>,       // Normalized numeric features
     pub mask_pad: Tensor<B, 2, Bool>, // Padding mask for the tokenized text
 }
+
+impl<B: Backend> Batcher<TrackClassificationItem, TextClassificationTrainingBatch<B>>
+    for SongClassificationBatcher<B>
+{
+    fn batch(&self, items: Vec<TrackClassificationItem>) -> TextClassificationTrainingBatch<B> {
+        let tracks: Vec<&TrimmedTrack> = items.iter().map(|item| &item.track).collect();
+        let (tokens, mask_pad) = self.tokenize(&tracks);
+        let labels_list = items
+            .iter()
+            .map(|item| Tensor::from_data(Data::from([(item.label as i64).elem()])))
+            .collect();
+
+        TextClassificationTrainingBatch {
+            tokens,
+            features: self.features(&tracks),
+            labels: Tensor::cat(labels_list, 0).to_device(&self.device),
+            label_sets: multi_hot(&items, self.n_classes, &self.device),
+            mask_pad,
+        }
+    }
+}
+
+impl<B: Backend> Batcher<TrimmedTrack, TextClassificationInferenceBatch<B>>
+    for SongClassificationBatcher<B>
+{
+    fn batch(&self, items: Vec<TrimmedTrack>) -> TextClassificationInferenceBatch<B> {
+        let tracks: Vec<&TrimmedTrack> = items.iter().collect();
+        let (tokens, mask_pad) = self.tokenize(&tracks);
+
+        TextClassificationInferenceBatch {
+            tokens,
+            features: self.features(&tracks),
+            mask_pad,
+        }
+    }
+}
+
+/// Batches the raw per-segment pitch and timbre values into padded sequences for sequence models.
+pub struct SegmentSequenceBatcher<B: Backend> {
+    device: B::Device,
+    max_segments: usize,
+    // Keep every `stride`th segment, 1 keeps all of them
+    stride: usize,
+    n_classes: usize,
+}
+
+impl<B: Backend> SegmentSequenceBatcher<B> {
+    pub fn new(device: B::Device, max_segments: usize, stride: usize, n_classes: usize) -> Self {
+        Self {
+            device,
+            max_segments,
+            stride: stride.max(1),
+            n_classes,
+        }
+    }
+
+    // Returns a [batch, segments, SEGMENT_FEATURE_COUNT] tensor and its padding mask
+    fn sequences(&self, tracks: &[&TrimmedTrack]) -> (Tensor<B, 3>, Tensor<B, 2, Bool>) {
+        let sequences: Vec<Vec<Vec<f32>>> = tracks
+            .iter()
+            .map(|track| {
+                track
+                    .segment_features()
+                    .into_iter()
+                    .step_by(self.stride)
+                    .take(self.max_segments)
+                    .collect()
+            })
+            .collect();
+        // At least one position so tracks without any analysis still produce a valid sequence
+        let seq_length = sequences.iter().map(Vec::len).max().unwrap_or(0).max(1);
+
+        let mut values = Vec::with_capacity(tracks.len() * seq_length * SEGMENT_FEATURE_COUNT);
+        let mut mask = Vec::with_capacity(tracks.len() * seq_length);
+        for sequence in sequences.iter() {
+            for position in 0..seq_length {
+                match sequence.get(position) {
+                    Some(segment) => {
+                        values.extend(segment.iter().cloned());
+                        mask.push(false);
+                    }
+                    None => {
+                        values.extend([0.0; SEGMENT_FEATURE_COUNT]);
+                        // Never mask the whole sequence, attention over nothing gives NaNs
+                        mask.push(position != 0 || !sequence.is_empty());
+                    }
+                }
+            }
+        }
+
+        let segments = Data::new(
+            values,
+            Shape::new([tracks.len(), seq_length, SEGMENT_FEATURE_COUNT]),
+        );
+        let mask = Data::new(mask, Shape::new([tracks.len(), seq_length]));
+        (
+            Tensor::from_data(segments.convert()).to_device(&self.device),
+            Tensor::from_data(mask).to_device(&self.device),
+        )
+    }
+}
+
+/// Struct for segment sequence training batch
+#[derive(Debug, Clone, new)]
+pub struct SegmentSequenceTrainingBatch<B: Backend> {
+    pub segments: Tensor<B, 3>,       // Pitches and timbre of each segment
+    pub labels: Tensor<B, 1, Int>,    // Labels of the tracks
+    pub label_sets: Tensor<B, 2>,     // Every label of the tracks as multi-hot vectors
+    pub mask_pad: Tensor<B, 2, Bool>, // Padding mask for the segments
+}
+
+impl<B: Backend> LabelSets<B> for SegmentSequenceTrainingBatch<B> {
+    fn label_sets(&self) -> Tensor<B, 2> {
+        self.label_sets.clone()
+    }
+}
+
+/// Struct for segment sequence inference batch
+#[derive(Debug, Clone, new)]
+pub struct SegmentSequenceInferenceBatch<B: Backend> {
+    pub segments: Tensor<B, 3>,       // Pitches and timbre of each segment
+    pub mask_pad: Tensor<B, 2, Bool>, // Padding mask for the segments
+}
+
+impl<B: Backend> Batcher<TrackClassificationItem, SegmentSequenceTrainingBatch<B>>
+    for SegmentSequenceBatcher<B>
+{
+    fn batch(&self, items: Vec<TrackClassificationItem>) -> SegmentSequenceTrainingBatch<B> {
+        let tracks: Vec<&TrimmedTrack> = items.iter().map(|item| &item.track).collect();
+        let (segments, mask_pad) = self.sequences(&tracks);
+        let labels_list = items
+            .iter()
+            .map(|item| Tensor::from_data(Data::from([(item.label as i64).elem()])))
+            .collect();
+
+        SegmentSequenceTrainingBatch {
+            segments,
+            labels: Tensor::cat(labels_list, 0).to_device(&self.device),
+            label_sets: multi_hot(&items, self.n_classes, &self.device),
+            mask_pad,
+        }
+    }
+}
+
+impl<B: Backend> Batcher<TrimmedTrack, SegmentSequenceInferenceBatch<B>>
+    for SegmentSequenceBatcher<B>
+{
+    fn batch(&self, items: Vec<TrimmedTrack>) -> SegmentSequenceInferenceBatch<B> {
+        let tracks: Vec<&TrimmedTrack> = items.iter().collect();
+        let (segments, mask_pad) = self.sequences(&tracks);
+
+        SegmentSequenceInferenceBatch { segments, mask_pad }
+    }
+}
+
+/// Combines the text/feature and segment sequence batchers for models that use all of them.
+pub struct FusionBatcher<B: Backend> {
+    song: SongClassificationBatcher<B>,
+    sequence: SegmentSequenceBatcher<B>,
+}
+
+impl<B: Backend> FusionBatcher<B> {
+    pub fn new(song: SongClassificationBatcher<B>, sequence: SegmentSequenceBatcher<B>) -> Self {
+        Self { song, sequence }
+    }
+}
+
+/// Struct for fusion training batch
+#[derive(Debug, Clone, new)]
+pub struct FusionTrainingBatch<B: Backend> {
+    pub song: TextClassificationTrainingBatch<B>, // Text tokens, features and labels
+    pub segments: Tensor<B, 3>,                   // Pitches and timbre of each segment
+    pub segments_mask_pad: Tensor<B, 2, Bool>,    // Padding mask for the segments
+}
+
+impl<B: Backend> LabelSets<B> for FusionTrainingBatch<B> {
+    fn label_sets(&self) -> Tensor<B, 2> {
+        self.song.label_sets.clone()
+    }
+}
+
+/// Struct for fusion inference batch
+#[derive(Debug, Clone, new)]
+pub struct FusionInferenceBatch<B: Backend> {
+    pub song: TextClassificationInferenceBatch<B>, // Text tokens and features
+    pub segments: Tensor<B, 3>,                    // Pitches and timbre of each segment
+    pub segments_mask_pad: Tensor<B, 2, Bool>,     // Padding mask for the segments
+}
+
+impl<B: Backend> Batcher<TrackClassificationItem, FusionTrainingBatch<B>> for FusionBatcher<B> {
+    fn batch(&self, items: Vec<TrackClassificationItem>) -> FusionTrainingBatch<B> {
+        let tracks: Vec<&TrimmedTrack> = items.iter().map(|item| &item.track).collect();
+        let (segments, segments_mask_pad) = self.sequence.sequences(&tracks);
+
+        FusionTrainingBatch {
+            song: self.song.batch(items),
+            segments,
+            segments_mask_pad,
+        }
+    }
+}
+
+impl<B: Backend> Batcher<TrimmedTrack, FusionInferenceBatch<B>> for FusionBatcher<B> {
+    fn batch(&self, items: Vec<TrimmedTrack>) -> FusionInferenceBatch<B> {
+        let tracks: Vec<&TrimmedTrack> = items.iter().collect();
+        let (segments, segments_mask_pad) = self.sequence.sequences(&tracks);
+
+        FusionInferenceBatch {
+            song: self.song.batch(items),
+            segments,
+            segments_mask_pad,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "backend-ndarray"))]
+mod tests {
+    use super::*;
+    use crate::normalizer::NormalizationKind;
+    use burn::backend::{ndarray::NdArrayDevice, NdArray};
+
+    type B = NdArray;
+
+    // One token per character so sequence lengths are easy to predict
+    struct CharTokenizer;
+
+    impl Tokenizer for CharTokenizer {
+        fn encode(&self, value: &str) -> Vec<usize> {
+            value.chars().map(|c| c as usize % 100 + 1).collect()
+        }
+
+        fn decode(&self, _tokens: &[usize]) -> String {
+            String::new()
+        }
+
+        fn vocab_size(&self) -> usize {
+            101
+        }
+
+        fn pad_token(&self) -> usize {
+            0
+        }
+    }
+
+    fn item(track: TrimmedTrack, labels: Vec<usize>) -> TrackClassificationItem {
+        TrackClassificationItem::new(track, labels[0], labels)
+    }
+
+    fn song_batcher(
+        tracks: &[TrimmedTrack],
+        max_seq_length: usize,
+    ) -> SongClassificationBatcher<B> {
+        let rows: Vec<Vec<f32>> = tracks.iter().map(|x| x.feature_vector()).collect();
+        SongClassificationBatcher::new(
+            Arc::new(CharTokenizer),
+            Arc::new(Normalizer::fit(NormalizationKind::ZScore, &rows).unwrap()),
+            NdArrayDevice::Cpu,
+            max_seq_length,
+            3,
+        )
+    }
+
+    #[test]
+    fn song_batch_pads_tokens_to_the_longest_description() {
+        // "ab — c — " is 9 characters, "abcdef — g — " is 13
+        let tracks = vec![
+            TrimmedTrack::fake("1", "ab", &["c"], 0),
+            TrimmedTrack::fake("2", "abcdef", &["g"], 0),
+        ];
+        let batcher = song_batcher(&tracks, 64);
+        let batch: TextClassificationTrainingBatch<B> = batcher.batch(vec![
+            item(tracks[0].clone(), vec![0, 2]),
+            item(tracks[1].clone(), vec![1]),
+        ]);
+
+        assert_eq!(batch.tokens.dims(), [2, 13]);
+        assert_eq!(batch.mask_pad.dims(), [2, 13]);
+        assert_eq!(batch.features.dims(), [2, FEATURE_COUNT]);
+        let mask = batch.mask_pad.into_data().value;
+        assert!(mask[..9].iter().all(|x| !x));
+        assert!(mask[9..13].iter().all(|x| *x));
+        assert!(mask[13..].iter().all(|x| !x));
+        let tokens = batch.tokens.into_data().convert::<i64>().value;
+        assert!(tokens[9..13].iter().all(|x| *x == 0));
+    }
+
+    #[test]
+    fn song_batch_truncates_to_max_seq_length() {
+        let tracks = vec![TrimmedTrack::fake("1", "a long track name", &["c"], 0)];
+        let batcher = song_batcher(&tracks, 4);
+        let batch: TextClassificationInferenceBatch<B> = batcher.batch(tracks);
+
+        assert_eq!(batch.tokens.dims(), [1, 4]);
+        assert!(batch.mask_pad.into_data().value.iter().all(|x| !x));
+    }
+
+    #[test]
+    fn song_batch_stacks_labels_and_label_sets() {
+        let tracks = vec![
+            TrimmedTrack::fake("1", "a", &["b"], 0),
+            TrimmedTrack::fake("2", "c", &["d"], 0),
+        ];
+        let batcher = song_batcher(&tracks, 64);
+        let batch: TextClassificationTrainingBatch<B> = batcher.batch(vec![
+            item(tracks[0].clone(), vec![0, 2]),
+            item(tracks[1].clone(), vec![1]),
+        ]);
+
+        assert_eq!(batch.labels.into_data().convert::<i64>().value, vec![0, 1]);
+        assert_eq!(batch.label_sets.dims(), [2, 3]);
+        assert_eq!(
+            batch.label_sets.into_data().convert::<f32>().value,
+            vec![1.0, 0.0, 1.0, 0.0, 1.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn segment_batch_pads_and_masks_shorter_sequences() {
+        let batcher = SegmentSequenceBatcher::<B>::new(NdArrayDevice::Cpu, 10, 1, 3);
+        let batch: SegmentSequenceTrainingBatch<B> = batcher.batch(vec![
+            item(TrimmedTrack::fake("1", "a", &[], 3), vec![0]),
+            item(TrimmedTrack::fake("2", "b", &[], 1), vec![1]),
+        ]);
+
+        assert_eq!(batch.segments.dims(), [2, 3, SEGMENT_FEATURE_COUNT]);
+        assert_eq!(
+            batch.mask_pad.into_data().value,
+            vec![false, false, false, false, true, true]
+        );
+        let segments = batch.segments.into_data().convert::<f32>().value;
+        // Second track, second position is padding
+        let padding = &segments[4 * SEGMENT_FEATURE_COUNT..5 * SEGMENT_FEATURE_COUNT];
+        assert!(padding.iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn segment_batch_keeps_one_unmasked_position_without_segments() {
+        let batcher = SegmentSequenceBatcher::<B>::new(NdArrayDevice::Cpu, 10, 1, 3);
+        let batch: SegmentSequenceInferenceBatch<B> = batcher.batch(vec![
+            TrimmedTrack::fake("1", "a", &[], 2),
+            TrimmedTrack::fake("2", "b", &[], 0),
+        ]);
+
+        assert_eq!(batch.segments.dims(), [2, 2, SEGMENT_FEATURE_COUNT]);
+        assert_eq!(
+            batch.mask_pad.into_data().value,
+            vec![false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn segment_batch_applies_stride_and_max_segments() {
+        let batcher = SegmentSequenceBatcher::<B>::new(NdArrayDevice::Cpu, 3, 2, 3);
+        let batch: SegmentSequenceInferenceBatch<B> =
+            batcher.batch(vec![TrimmedTrack::fake("1", "a", &[], 10)]);
+
+        assert_eq!(batch.segments.dims(), [1, 3, SEGMENT_FEATURE_COUNT]);
+        // Fake pitches are the segment index, every second one is kept
+        let segments = batch.segments.into_data().convert::<f32>().value;
+        let pitches: Vec<f32> = (0..3)
+            .map(|i| segments[i * SEGMENT_FEATURE_COUNT])
+            .collect();
+        assert_eq!(pitches, vec![0.0, 2.0, 4.0]);
+    }
+}
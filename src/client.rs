@@ -0,0 +1,267 @@
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use rspotify::clients::{BaseClient, OAuthClient};
+use rspotify::model::{PlayableId, PlayableItem, PlaylistId, TrackId};
+use rspotify::prelude::Id;
+
+/// Spotify allows at most this many tracks in a single add or remove request.
+pub const MAX_ITEMS_PER_REQUEST: usize = 100;
+
+/// A playlist owned or followed by the user.
+#[derive(Debug, Clone)]
+pub struct PlaylistSummary {
+    pub id: String,
+    pub name: String,
+    /// Whether the current user owns it, followed playlists can't have been created by the tool.
+    pub owned: bool,
+}
+
+/// The playlist operations the tool needs, so they can be swapped out for a fake account.
+/// Track and playlist ids are the bare base62 ids, not URIs.
+pub trait PlaylistClient {
+    async fn user_playlists(&self) -> Result<Vec<PlaylistSummary>>;
+
+    /// Creates a playlist for the current user and returns its id.
+    async fn create_playlist(&self, name: &str, description: &str, public: bool) -> Result<String>;
+
+    async fn rename_playlist(&self, playlist_id: &str, name: &str) -> Result<()>;
+
+    /// The description of a playlist, empty if it has none.
+    async fn playlist_description(&self, playlist_id: &str) -> Result<String>;
+
+    async fn playlist_track_ids(&self, playlist_id: &str) -> Result<Vec<String>>;
+
+    /// Adds at most [`MAX_ITEMS_PER_REQUEST`] tracks to the end of the playlist.
+    async fn add_tracks(&self, playlist_id: &str, track_ids: &[String]) -> Result<()>;
+
+    /// Removes every occurrence of at most [`MAX_ITEMS_PER_REQUEST`] tracks from the playlist.
+    async fn remove_tracks(&self, playlist_id: &str, track_ids: &[String]) -> Result<()>;
+}
+
+impl PlaylistClient for rspotify::AuthCodeSpotify {
+    async fn user_playlists(&self) -> Result<Vec<PlaylistSummary>> {
+        let user = self.me().await.context("Error in getting current user")?;
+        let playlists = self
+            .current_user_playlists()
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .context("Error in getting user playlists")?;
+        Ok(playlists
+            .into_iter()
+            .map(|x| PlaylistSummary {
+                id: x.id.id().to_string(),
+                name: x.name,
+                owned: x.owner.id == user.id,
+            })
+            .collect())
+    }
+
+    async fn create_playlist(&self, name: &str, description: &str, public: bool) -> Result<String> {
+        let user = self.me().await.context("Error in getting current user")?;
+        let playlist = self
+            .user_playlist_create(user.id, name, Some(public), Some(false), Some(description))
+            .await
+            .with_context(|| format!("Error in creating playlist {name}"))?;
+        Ok(playlist.id.id().to_string())
+    }
+
+    async fn rename_playlist(&self, playlist_id: &str, name: &str) -> Result<()> {
+        let playlist_id = PlaylistId::from_id(playlist_id).context("Invalid playlist id")?;
+        self.playlist_change_detail(playlist_id, Some(name), None, None, None)
+            .await
+            .with_context(|| format!("Error in renaming playlist to {name}"))?;
+        Ok(())
+    }
+
+    async fn playlist_description(&self, playlist_id: &str) -> Result<String> {
+        let playlist_id = PlaylistId::from_id(playlist_id).context("Invalid playlist id")?;
+        let playlist = self
+            .playlist(playlist_id, None, None)
+            .await
+            .context("Error in getting playlist")?;
+        Ok(playlist.description.unwrap_or_default())
+    }
+
+    async fn playlist_track_ids(&self, playlist_id: &str) -> Result<Vec<String>> {
+        let playlist_id = PlaylistId::from_id(playlist_id).context("Invalid playlist id")?;
+        let items = self
+            .playlist_items(playlist_id, None, None)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .context("Error in getting playlist items")?;
+        // Episodes and local files without an id can't have been put there by this tool
+        Ok(items
+            .into_iter()
+            .filter_map(|item| match item.track? {
+                PlayableItem::Track(track) => Some(track.id?.id().to_string()),
+                PlayableItem::Episode(_) => None,
+            })
+            .collect())
+    }
+
+    async fn add_tracks(&self, playlist_id: &str, track_ids: &[String]) -> Result<()> {
+        let playlist_id = PlaylistId::from_id(playlist_id).context("Invalid playlist id")?;
+        self.playlist_add_items(playlist_id, playable_ids(track_ids)?, None)
+            .await
+            .context("Error in adding tracks to playlist")?;
+        Ok(())
+    }
+
+    async fn remove_tracks(&self, playlist_id: &str, track_ids: &[String]) -> Result<()> {
+        let playlist_id = PlaylistId::from_id(playlist_id).context("Invalid playlist id")?;
+        self.playlist_remove_all_occurrences_of_items(playlist_id, playable_ids(track_ids)?, None)
+            .await
+            .context("Error in removing tracks from playlist")?;
+        Ok(())
+    }
+}
+
+fn playable_ids(track_ids: &[String]) -> Result<Vec<PlayableId<'static>>> {
+    track_ids
+        .iter()
+        .map(|id| {
+            TrackId::from_id(id.clone())
+                .map(PlayableId::Track)
+                .with_context(|| format!("Invalid track id {id}"))
+        })
+        .collect()
+}
+
+/// In-memory account to test code that reads and edits playlists without touching Spotify.
+#[cfg(test)]
+pub mod fake {
+    use std::cell::RefCell;
+
+    use anyhow::{bail, Context, Result};
+
+    use super::{PlaylistClient, PlaylistSummary, MAX_ITEMS_PER_REQUEST};
+
+    #[derive(Debug, Clone)]
+    pub struct FakePlaylist {
+        pub id: String,
+        pub name: String,
+        pub description: String,
+        pub owned: bool,
+        pub track_ids: Vec<String>,
+    }
+
+    #[derive(Debug, Default)]
+    pub struct FakeClient {
+        pub playlists: RefCell<Vec<FakePlaylist>>,
+        /// Number of tracks in every add or remove request, in order.
+        pub requests: RefCell<Vec<usize>>,
+        /// Add and remove requests after this many fail.
+        pub fail_after: Option<usize>,
+    }
+
+    impl FakeClient {
+        /// Adds a playlist and returns its id.
+        pub fn add_playlist(
+            &self,
+            name: &str,
+            description: &str,
+            owned: bool,
+            track_ids: &[&str],
+        ) -> String {
+            let mut playlists = self.playlists.borrow_mut();
+            let id = format!("playlist{}", playlists.len());
+            playlists.push(FakePlaylist {
+                id: id.clone(),
+                name: name.to_string(),
+                description: description.to_string(),
+                owned,
+                track_ids: track_ids.iter().map(|x| x.to_string()).collect(),
+            });
+            id
+        }
+
+        pub fn track_ids(&self, playlist_id: &str) -> Vec<String> {
+            self.playlists
+                .borrow()
+                .iter()
+                .find(|x| x.id == playlist_id)
+                .map(|x| x.track_ids.clone())
+                .unwrap_or_default()
+        }
+
+        fn edit(&self, playlist_id: &str, edit: impl FnOnce(&mut FakePlaylist)) -> Result<()> {
+            let mut playlists = self.playlists.borrow_mut();
+            let playlist = playlists
+                .iter_mut()
+                .find(|x| x.id == playlist_id)
+                .context("No such playlist")?;
+            edit(playlist);
+            Ok(())
+        }
+
+        fn request(&self, track_ids: &[String]) -> Result<()> {
+            if track_ids.len() > MAX_ITEMS_PER_REQUEST {
+                bail!("Too many tracks in one request");
+            }
+            if self.fail_after == Some(self.requests.borrow().len()) {
+                bail!("Request failed");
+            }
+            self.requests.borrow_mut().push(track_ids.len());
+            Ok(())
+        }
+    }
+
+    impl PlaylistClient for FakeClient {
+        async fn user_playlists(&self) -> Result<Vec<PlaylistSummary>> {
+            Ok(self
+                .playlists
+                .borrow()
+                .iter()
+                .map(|x| PlaylistSummary {
+                    id: x.id.clone(),
+                    name: x.name.clone(),
+                    owned: x.owned,
+                })
+                .collect())
+        }
+
+        async fn create_playlist(
+            &self,
+            name: &str,
+            description: &str,
+            _public: bool,
+        ) -> Result<String> {
+            Ok(self.add_playlist(name, description, true, &[]))
+        }
+
+        async fn rename_playlist(&self, playlist_id: &str, name: &str) -> Result<()> {
+            self.edit(playlist_id, |x| x.name = name.to_string())
+        }
+
+        async fn playlist_description(&self, playlist_id: &str) -> Result<String> {
+            self.playlists
+                .borrow()
+                .iter()
+                .find(|x| x.id == playlist_id)
+                .map(|x| x.description.clone())
+                .context("No such playlist")
+        }
+
+        async fn playlist_track_ids(&self, playlist_id: &str) -> Result<Vec<String>> {
+            Ok(self.track_ids(playlist_id))
+        }
+
+        async fn add_tracks(&self, playlist_id: &str, track_ids: &[String]) -> Result<()> {
+            self.request(track_ids)?;
+            self.edit(playlist_id, |x| {
+                x.track_ids.extend(track_ids.iter().cloned())
+            })
+        }
+
+        async fn remove_tracks(&self, playlist_id: &str, track_ids: &[String]) -> Result<()> {
+            self.request(track_ids)?;
+            self.edit(playlist_id, |x| {
+                x.track_ids.retain(|id| !track_ids.contains(id))
+            })
+        }
+    }
+}
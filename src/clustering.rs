@@ -0,0 +1,474 @@
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+/// How the motherlist is grouped by [`discover`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ClusterMethod {
+    /// K-means, the number of clusters with the best silhouette wins
+    #[default]
+    Kmeans,
+    /// Gaussian mixture with a diagonal covariance, the number of clusters with the lowest BIC
+    /// wins
+    Gmm,
+}
+
+/// The best clustering found by [`discover`].
+#[derive(Debug, Clone)]
+pub struct Discovery {
+    /// Score of every number of clusters tried, silhouette for k-means and BIC for mixtures.
+    pub scores: Vec<(usize, f64)>,
+    pub clusters: Vec<Cluster>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    /// Indices of the points in the cluster, most representative first.
+    pub members: Vec<usize>,
+    /// Mean of the members, in the same space as the points.
+    pub center: Vec<f32>,
+}
+
+impl Cluster {
+    /// Features whose mean differs most from the mean over every point, largest first. With
+    /// z-scored points the values are in standard deviations.
+    pub fn distinguishing_features(&self, n: usize) -> Vec<(usize, f32)> {
+        let mut features: Vec<(usize, f32)> = self.center.iter().cloned().enumerate().collect();
+        features.sort_by(|a, b| b.1.abs().total_cmp(&a.1.abs()));
+        features.truncate(n);
+        features
+    }
+}
+
+/// Clusters `points` once for every number of clusters in `min_k..=max_k` and keeps the best.
+/// Points should be normalized so every feature weighs the same.
+pub fn discover(
+    points: &[Vec<f32>],
+    method: ClusterMethod,
+    min_k: usize,
+    max_k: usize,
+    seed: u64,
+) -> Discovery {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let max_k = max_k.min(points.len());
+    let mut best: Option<(f64, Vec<usize>)> = None;
+    let mut scores = vec![];
+    for k in min_k.max(1)..=max_k {
+        let (assignments, score) = match method {
+            ClusterMethod::Kmeans => {
+                let assignments = kmeans(points, k, &mut rng).assignments;
+                let score = silhouette(points, &assignments, k, &mut rng);
+                scores.push((k, score));
+                (assignments, score)
+            }
+            ClusterMethod::Gmm => {
+                let mixture = gaussian_mixture(points, k, &mut rng);
+                let bic = mixture.bic(points.len());
+                scores.push((k, bic));
+                // Lower BIC is better, negated so both methods keep the highest score
+                (mixture.assignments, -bic)
+            }
+        };
+        if best.as_ref().map_or(true, |(x, _)| score > *x) {
+            best = Some((score, assignments));
+        }
+    }
+
+    let clusters = best
+        .map(|(_, assignments)| clusters(points, &assignments))
+        .unwrap_or_default();
+    Discovery { scores, clusters }
+}
+
+// Groups the points by cluster, sorting every cluster's members by distance to its center
+fn clusters(points: &[Vec<f32>], assignments: &[usize]) -> Vec<Cluster> {
+    let k = assignments.iter().max().map_or(0, |x| x + 1);
+    let mut clusters: Vec<Cluster> = (0..k)
+        .map(|cluster| {
+            let members: Vec<usize> = (0..points.len())
+                .filter(|i| assignments[*i] == cluster)
+                .collect();
+            Cluster {
+                center: mean(points, &members),
+                members,
+            }
+        })
+        .filter(|x| !x.members.is_empty())
+        .collect();
+    clusters.iter_mut().for_each(|cluster| {
+        let center = cluster.center.clone();
+        cluster.members.sort_by(|a, b| {
+            squared_distance(&points[*a], &center)
+                .total_cmp(&squared_distance(&points[*b], &center))
+        })
+    });
+    clusters.sort_by_key(|x| std::cmp::Reverse(x.members.len()));
+    clusters
+}
+
+struct KMeans {
+    centroids: Vec<Vec<f32>>,
+    assignments: Vec<usize>,
+    inertia: f32,
+}
+
+const KMEANS_RESTARTS: usize = 5;
+const MAX_ITERATIONS: usize = 100;
+
+// Lloyd's algorithm from k-means++ seeds, keeping the restart with the lowest inertia
+fn kmeans(points: &[Vec<f32>], k: usize, rng: &mut StdRng) -> KMeans {
+    (0..KMEANS_RESTARTS)
+        .map(|_| {
+            let mut centroids = kmeans_plus_plus(points, k, rng);
+            let mut assignments = vec![0; points.len()];
+            for _ in 0..MAX_ITERATIONS {
+                let next: Vec<usize> = points.iter().map(|x| nearest(x, &centroids).0).collect();
+                let changed = next != assignments;
+                assignments = next;
+                centroids = (0..k)
+                    .map(|cluster| {
+                        let members: Vec<usize> = (0..points.len())
+                            .filter(|i| assignments[*i] == cluster)
+                            .collect();
+                        match members.is_empty() {
+                            // An empty cluster restarts from a random point
+                            true => points.choose(rng).cloned().unwrap_or_default(),
+                            false => mean(points, &members),
+                        }
+                    })
+                    .collect();
+                if !changed {
+                    break;
+                }
+            }
+            let inertia = points.iter().map(|x| nearest(x, &centroids).1).sum();
+            KMeans {
+                centroids,
+                assignments,
+                inertia,
+            }
+        })
+        .min_by(|a, b| a.inertia.total_cmp(&b.inertia))
+        .expect("k-means runs at least once")
+}
+
+// Every next seed is picked with a probability proportional to its squared distance to the
+// closest seed so far
+fn kmeans_plus_plus(points: &[Vec<f32>], k: usize, rng: &mut StdRng) -> Vec<Vec<f32>> {
+    let mut centroids = vec![points[rng.gen_range(0..points.len())].clone()];
+    while centroids.len() < k {
+        let distances: Vec<f32> = points.iter().map(|x| nearest(x, &centroids).1).collect();
+        let total: f32 = distances.iter().sum();
+        if total <= 0.0 {
+            centroids.push(points[rng.gen_range(0..points.len())].clone());
+            continue;
+        }
+        let mut target = rng.gen_range(0.0..total);
+        let next = distances
+            .iter()
+            .position(|x| {
+                target -= x;
+                target <= 0.0
+            })
+            .unwrap_or(points.len() - 1);
+        centroids.push(points[next].clone());
+    }
+    centroids
+}
+
+// Silhouettes cost a distance per pair of points, so big motherlists are scored on a sample
+const SILHOUETTE_SAMPLE: usize = 2000;
+
+// Mean silhouette of the clustering, from -1 to 1, higher means tighter and better separated
+// clusters
+fn silhouette(points: &[Vec<f32>], assignments: &[usize], k: usize, rng: &mut StdRng) -> f64 {
+    if k < 2 {
+        return 0.0;
+    }
+    let mut sample: Vec<usize> = (0..points.len()).collect();
+    sample.shuffle(rng);
+    sample.truncate(SILHOUETTE_SAMPLE);
+
+    let scores: Vec<f64> = sample
+        .iter()
+        .filter_map(|i| {
+            let mut sums = vec![0.0f64; k];
+            let mut counts = vec![0usize; k];
+            sample.iter().filter(|j| *j != i).for_each(|j| {
+                sums[assignments[*j]] += squared_distance(&points[*i], &points[*j]).sqrt() as f64;
+                counts[assignments[*j]] += 1;
+            });
+            let own = assignments[*i];
+            // Silhouettes of points alone in their cluster are left out
+            if counts[own] == 0 {
+                return None;
+            }
+            let a = sums[own] / counts[own] as f64;
+            let b = (0..k)
+                .filter(|x| *x != own && counts[*x] > 0)
+                .map(|x| sums[x] / counts[x] as f64)
+                .min_by(|a, b| a.total_cmp(b))?;
+            Some((b - a) / a.max(b).max(f64::EPSILON))
+        })
+        .collect();
+    match scores.is_empty() {
+        true => 0.0,
+        false => scores.iter().sum::<f64>() / scores.len() as f64,
+    }
+}
+
+struct GaussianMixture {
+    weights: Vec<f64>,
+    means: Vec<Vec<f32>>,
+    variances: Vec<Vec<f32>>,
+    assignments: Vec<usize>,
+    log_likelihood: f64,
+}
+
+impl GaussianMixture {
+    /// Bayesian information criterion, lower is better. Each component has a mean and a variance
+    /// per feature and all but one a weight.
+    fn bic(&self, n: usize) -> f64 {
+        let k = self.means.len();
+        let d = self.means.first().map_or(0, |x| x.len());
+        let parameters = (k * 2 * d + k - 1) as f64;
+        parameters * (n as f64).ln() - 2.0 * self.log_likelihood
+    }
+}
+
+// Keeps variances away from zero for features that are constant within a cluster
+const MIN_VARIANCE: f32 = 1e-3;
+
+// Expectation maximization of a diagonal Gaussian mixture, started from k-means
+fn gaussian_mixture(points: &[Vec<f32>], k: usize, rng: &mut StdRng) -> GaussianMixture {
+    let n = points.len();
+    let d = points.first().map_or(0, |x| x.len());
+    let start = kmeans(points, k, rng);
+    let mut mixture = GaussianMixture {
+        weights: vec![1.0 / k as f64; k],
+        means: start.centroids,
+        variances: vec![vec![1.0; d]; k],
+        assignments: start.assignments,
+        log_likelihood: f64::NEG_INFINITY,
+    };
+
+    for _ in 0..MAX_ITERATIONS {
+        // E step, responsibilities in log space for stability
+        let mut log_likelihood = 0.0;
+        let responsibilities: Vec<Vec<f64>> = points
+            .iter()
+            .map(|x| {
+                let logs: Vec<f64> = (0..k)
+                    .map(|c| {
+                        mixture.weights[c].max(f64::MIN_POSITIVE).ln()
+                            + log_density(x, &mixture.means[c], &mixture.variances[c])
+                    })
+                    .collect();
+                let max = logs.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                let total = max + logs.iter().map(|x| (x - max).exp()).sum::<f64>().ln();
+                log_likelihood += total;
+                logs.iter().map(|x| (x - total).exp()).collect()
+            })
+            .collect();
+
+        // M step
+        for c in 0..k {
+            let weight: f64 = responsibilities.iter().map(|r| r[c]).sum();
+            if weight <= f64::EPSILON {
+                continue;
+            }
+            mixture.weights[c] = weight / n as f64;
+            mixture.means[c] = (0..d)
+                .map(|f| {
+                    (points
+                        .iter()
+                        .zip(responsibilities.iter())
+                        .map(|(x, r)| r[c] * x[f] as f64)
+                        .sum::<f64>()
+                        / weight) as f32
+                })
+                .collect();
+            mixture.variances[c] = (0..d)
+                .map(|f| {
+                    let mean = mixture.means[c][f];
+                    let variance = points
+                        .iter()
+                        .zip(responsibilities.iter())
+                        .map(|(x, r)| r[c] * ((x[f] - mean) as f64).powi(2))
+                        .sum::<f64>()
+                        / weight;
+                    (variance as f32).max(MIN_VARIANCE)
+                })
+                .collect();
+        }
+        mixture.assignments = responsibilities
+            .iter()
+            .map(|r| {
+                r.iter()
+                    .enumerate()
+                    .max_by(|a, b| a.1.total_cmp(b.1))
+                    .map_or(0, |x| x.0)
+            })
+            .collect();
+
+        let converged = (log_likelihood - mixture.log_likelihood).abs() < 1e-4 * n as f64;
+        mixture.log_likelihood = log_likelihood;
+        if converged {
+            break;
+        }
+    }
+    mixture
+}
+
+fn log_density(x: &[f32], mean: &[f32], variance: &[f32]) -> f64 {
+    x.iter()
+        .zip(mean.iter())
+        .zip(variance.iter())
+        .map(|((x, m), v)| {
+            let (x, m, v) = (*x as f64, *m as f64, *v as f64);
+            -0.5 * ((2.0 * std::f64::consts::PI * v).ln() + (x - m).powi(2) / v)
+        })
+        .sum()
+}
+
+// Index of and squared distance to the closest centroid
+fn nearest(point: &[f32], centroids: &[Vec<f32>]) -> (usize, f32) {
+    centroids
+        .iter()
+        .map(|x| squared_distance(point, x))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap_or((0, 0.0))
+}
+
+fn mean(points: &[Vec<f32>], members: &[usize]) -> Vec<f32> {
+    let d = points.first().map_or(0, |x| x.len());
+    let mut mean = vec![0.0; d];
+    members.iter().for_each(|i| {
+        mean.iter_mut()
+            .zip(points[*i].iter())
+            .for_each(|(m, x)| *m += x)
+    });
+    mean.iter_mut()
+        .for_each(|m| *m /= members.len().max(1) as f32);
+    mean
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y).powi(2)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CENTERS: [[f32; 2]; 3] = [[0.0, 0.0], [10.0, 0.0], [0.0, 10.0]];
+    const PER_BLOB: usize = 30;
+
+    // Three well separated Gaussian blobs and the blob of every point
+    fn blobs() -> (Vec<Vec<f32>>, Vec<usize>) {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut normal = || {
+            let (u, v): (f32, f32) = (1.0 - rng.gen::<f32>(), rng.gen());
+            (-2.0 * u.ln()).sqrt() * (2.0 * std::f32::consts::PI * v).cos() * 0.5
+        };
+        let mut points = vec![];
+        let mut truth = vec![];
+        for (blob, center) in CENTERS.iter().enumerate() {
+            for _ in 0..PER_BLOB {
+                points.push(vec![center[0] + normal(), center[1] + normal()]);
+                truth.push(blob);
+            }
+        }
+        (points, truth)
+    }
+
+    // Whether the assignments group the points exactly like the blobs, up to cluster ids
+    fn same_grouping(assignments: &[usize], truth: &[usize]) -> bool {
+        (0..truth.len()).all(|i| {
+            (0..truth.len()).all(|j| (assignments[i] == assignments[j]) == (truth[i] == truth[j]))
+        })
+    }
+
+    #[test]
+    fn kmeans_finds_the_blobs() {
+        let (points, truth) = blobs();
+        let mut rng = StdRng::seed_from_u64(1);
+        let result = kmeans(&points, 3, &mut rng);
+        assert!(same_grouping(&result.assignments, &truth));
+        for center in CENTERS {
+            let (_, distance) = nearest(&center, &result.centroids);
+            assert!(distance < 0.25, "no centroid near {center:?}");
+        }
+    }
+
+    #[test]
+    fn silhouette_prefers_the_true_grouping() {
+        let (points, truth) = blobs();
+        let mut rng = StdRng::seed_from_u64(1);
+        let good = silhouette(&points, &truth, 3, &mut rng);
+        assert!(good > 0.8, "silhouette of the blobs is {good}");
+
+        // Merging two blobs and splitting the third in half scores worse
+        let bad: Vec<usize> = truth
+            .iter()
+            .enumerate()
+            .map(|(i, blob)| match blob {
+                0 | 1 => 0,
+                _ => 1 + i % 2,
+            })
+            .collect();
+        assert!(silhouette(&points, &bad, 3, &mut rng) < good);
+        assert_eq!(
+            silhouette(&points, &vec![0; points.len()], 1, &mut rng),
+            0.0
+        );
+    }
+
+    #[test]
+    fn discover_picks_the_number_of_blobs() {
+        let (points, truth) = blobs();
+        for method in [ClusterMethod::Kmeans, ClusterMethod::Gmm] {
+            let discovery = discover(&points, method, 2, 6, 42);
+            assert_eq!(discovery.scores.len(), 5);
+            assert_eq!(discovery.clusters.len(), 3, "{method:?}");
+
+            let mut assignments = vec![0; points.len()];
+            discovery
+                .clusters
+                .iter()
+                .enumerate()
+                .for_each(|(c, cluster)| {
+                    assert_eq!(cluster.members.len(), PER_BLOB);
+                    cluster.members.iter().for_each(|x| assignments[*x] = c);
+                });
+            assert!(same_grouping(&assignments, &truth), "{method:?}");
+        }
+    }
+
+    #[test]
+    fn bic_is_lowest_for_the_number_of_blobs() {
+        let (points, _) = blobs();
+        let mut rng = StdRng::seed_from_u64(1);
+        let bic: Vec<f64> = (1..=5)
+            .map(|k| gaussian_mixture(&points, k, &mut rng).bic(points.len()))
+            .collect();
+        let best = bic
+            .iter()
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(b.1))
+            .map(|x| x.0 + 1);
+        assert_eq!(best, Some(3), "BIC per number of clusters: {bic:?}");
+    }
+
+    #[test]
+    fn members_are_sorted_by_distance_to_the_center() {
+        let (points, truth) = blobs();
+        for cluster in clusters(&points, &truth) {
+            let distances: Vec<f32> = cluster
+                .members
+                .iter()
+                .map(|x| squared_distance(&points[*x], &cluster.center))
+                .collect();
+            assert!(distances.windows(2).all(|x| x[0] <= x[1]));
+        }
+    }
+}
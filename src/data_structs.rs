@@ -1,25 +1,51 @@
-use rspotify::{clients::BaseClient, model::FullTrack};
+use chrono::Datelike;
+use rspotify::{clients::BaseClient, model::FullTrack, prelude::Id};
 use serde::{Deserialize, Serialize};
 
 use crate::misc_helpers;
 
 use anyhow::{Context, Result};
 
+/// Features of [`TrimmedTrack::number_feature`].
+pub const NUMBER_FEATURES: &[&str] = &[
+    "duration",
+    "explicit",
+    "year",
+    "added_year",
+    "acousticness",
+    "danceability",
+    "energy",
+    "liveness",
+    "speechiness",
+    "valence",
+    "loudness",
+    "tempo",
+    "time_signature",
+    "key",
+    "mode",
+];
+
+/// Fields of [`TrimmedTrack::text_feature`].
+pub const TEXT_FEATURES: &[&str] = &["name", "artist", "album", "album_artist"];
+
 pub struct BetterSavedTrack {
     pub added_at: i64,
     pub track: FullTrack,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TrimmedTrack {
+    // Missing in databases written before tracks were identified, see `dataset::read_split`
+    #[serde(default)]
+    pub track_id: String,
     pub track_name: String,
     added_at: i64,
     duration: f32,
     explicit: bool,
-    album_name: String,
+    pub album_name: String,
     album_artists: Vec<String>,
     album_release_date: i64,
-    artists: Vec<String>,
+    pub artists: Vec<String>,
     acousticness: f32,
     danceability: f32,
     energy: f32,
@@ -86,6 +112,12 @@ impl TrimmedTrack {
             .context("Error getting track features")?;
 
         let best_track = TrimmedTrack {
+            track_id: track
+                .id
+                .as_ref()
+                .expect("Track should have track id")
+                .id()
+                .to_string(),
             track_name: track.name,
             added_at: saved_track.added_at,
             duration: analysis.track.duration,
@@ -183,6 +215,205 @@ impl TrimmedTrack {
         };
         Ok(best_track)
     }
+
+    /// Text used by the tokenizer, in the form "track — artists — album".
+    pub fn text_description(&self) -> String {
+        format!(
+            "{} — {} — {}",
+            self.track_name,
+            self.artists.join(", "),
+            self.album_name
+        )
+    }
+
+    /// Album release date as "YYYY-MM-DD", dates only known to the year or month are mid-period.
+    pub fn release_date(&self) -> String {
+        chrono::DateTime::from_timestamp(self.album_release_date, 0)
+            .map(|x| x.format("%Y-%m-%d").to_string())
+            .unwrap_or_default()
+    }
+
+    /// Value of a single number-like feature by name, see [`NUMBER_FEATURES`]. Booleans are 0 or
+    /// 1 and dates are years.
+    pub fn number_feature(&self, name: &str) -> Option<f32> {
+        let year = |timestamp: i64| {
+            chrono::DateTime::from_timestamp(timestamp, 0).map(|x| x.year() as f32)
+        };
+        Some(match name {
+            "duration" => self.duration,
+            "explicit" => self.explicit as u8 as f32,
+            "year" => year(self.album_release_date)?,
+            "added_year" => year(self.added_at)?,
+            "acousticness" => self.acousticness,
+            "danceability" => self.danceability,
+            "energy" => self.energy,
+            "liveness" => self.liveness,
+            "speechiness" => self.speechiness,
+            "valence" => self.valence,
+            "loudness" => self.loudness,
+            "tempo" => self.tempo,
+            "time_signature" => self.time_signature as f32,
+            "key" => self.key as f32,
+            "mode" => self.mode as f32,
+            _ => return None,
+        })
+    }
+
+    /// The names of a text field, see [`TEXT_FEATURES`]. Artists are a list, so a field can
+    /// have several values.
+    pub fn text_feature(&self, name: &str) -> Option<Vec<&str>> {
+        Some(match name {
+            "name" => vec![self.track_name.as_str()],
+            "artist" => self.artists.iter().map(|x| x.as_str()).collect(),
+            "album" => vec![self.album_name.as_str()],
+            "album_artist" => self.album_artists.iter().map(|x| x.as_str()).collect(),
+            _ => return None,
+        })
+    }
+
+    /// A few audio features that are easy to make sense of, for showing the track to a user.
+    pub fn highlights(&self) -> Vec<(&'static str, f32)> {
+        vec![
+            ("Danceability", self.danceability),
+            ("Energy", self.energy),
+            ("Valence", self.valence),
+            ("Acousticness", self.acousticness),
+            ("Tempo", self.tempo),
+            ("Loudness", self.loudness),
+        ]
+    }
+
+    /// Names of the entries of [`TrimmedTrack::feature_vector`], in the same order.
+    pub fn feature_names() -> Vec<String> {
+        let mut names: Vec<String> = [
+            "duration",
+            "explicit",
+            "added_at",
+            "album_release_date",
+            "acousticness",
+            "danceability",
+            "energy",
+            "liveness",
+            "mode",
+            "speechiness",
+            "valence",
+            "end_of_fade_in",
+            "start_of_fade_out",
+            "loudness",
+            "tempo",
+            "tempo_confidence",
+            "time_signature",
+            "time_signature_confidence",
+            "key",
+            "key_confidence",
+            "mode_confidence",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        names.extend((0..12).map(|i| format!("pitch_{i}")));
+        names.extend((0..12).map(|i| format!("timbre_{i}")));
+        names
+    }
+
+    /// Flattens the track into the fixed-length numeric vector used as model input.
+    /// Per-segment pitches and timbre are averaged over the whole track.
+    pub fn feature_vector(&self) -> Vec<f32> {
+        let mut features = vec![
+            self.duration,
+            if self.explicit { 1.0 } else { 0.0 },
+            self.added_at as f32,
+            self.album_release_date as f32,
+            self.acousticness,
+            self.danceability,
+            self.energy,
+            self.liveness,
+            self.mode as f32,
+            self.speechiness,
+            self.valence,
+            self.end_of_fade_in,
+            self.start_of_fade_out,
+            self.loudness,
+            self.tempo,
+            self.tempo_confidence,
+            self.time_signature as f32,
+            self.time_signature_confidence,
+            self.key as f32,
+            self.key_confidence,
+            self.mode_confidence,
+        ];
+        features.extend(mean_per_column(&self.segments_pitches, 12));
+        features.extend(mean_per_column(&self.segments_timbre, 12));
+        features
+    }
+
+    /// The per-segment time series, each segment being its 12 pitches followed by its 12 timbre
+    /// values.
+    pub fn segment_features(&self) -> Vec<Vec<f32>> {
+        self.segments_pitches
+            .iter()
+            .zip(self.segments_timbre.iter())
+            .map(|(pitches, timbre)| pitches.iter().chain(timbre.iter()).cloned().collect())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl TrimmedTrack {
+    /// Track without any audio features but `segments` segments of pitches and timbre.
+    pub fn fake(track_id: &str, track_name: &str, artists: &[&str], segments: usize) -> Self {
+        Self {
+            track_id: track_id.to_string(),
+            track_name: track_name.to_string(),
+            artists: artists.iter().map(|x| x.to_string()).collect(),
+            segments_pitches: (0..segments).map(|i| vec![i as f32; 12]).collect(),
+            segments_timbre: (0..segments).map(|i| vec![-(i as f32); 12]).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+// For the tests of the sublist rules
+#[cfg(test)]
+impl TrimmedTrack {
+    /// Sets a feature of [`TrimmedTrack::number_feature`], dates aren't supported.
+    pub fn with_feature(mut self, name: &str, value: f32) -> Self {
+        match name {
+            "duration" => self.duration = value,
+            "explicit" => self.explicit = value != 0.0,
+            "acousticness" => self.acousticness = value,
+            "danceability" => self.danceability = value,
+            "energy" => self.energy = value,
+            "liveness" => self.liveness = value,
+            "speechiness" => self.speechiness = value,
+            "valence" => self.valence = value,
+            "loudness" => self.loudness = value,
+            "tempo" => self.tempo = value,
+            "time_signature" => self.time_signature = value as i32,
+            "key" => self.key = value as u32,
+            "mode" => self.mode = value as i32,
+            _ => panic!("{name:?} can't be set on a fake track"),
+        }
+        self
+    }
+}
+
+/// Length of the vector returned by [`TrimmedTrack::feature_vector`].
+pub const FEATURE_COUNT: usize = 21 + 12 + 12;
+
+/// Number of values per segment returned by [`TrimmedTrack::segment_features`].
+pub const SEGMENT_FEATURE_COUNT: usize = 12 + 12;
+
+fn mean_per_column(rows: &[Vec<f32>], width: usize) -> Vec<f32> {
+    let mut sums = vec![0.0; width];
+    if rows.is_empty() {
+        return sums;
+    }
+    for row in rows {
+        sums.iter_mut().zip(row.iter()).for_each(|(sum, x)| *sum += x);
+    }
+    sums.iter_mut().for_each(|sum| *sum /= rows.len() as f32);
+    sums
 }
 
 // pub async fn get_tracks_details(
@@ -1,30 +1,100 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
 use std::path::Path;
 
-use burn::data::dataset::{Dataset, SqliteDatasetError, SqliteDatasetStorage};
+use burn::data::dataset::{Dataset, SqliteDataset, SqliteDatasetError, SqliteDatasetStorage};
 use derive_new::new;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 
-use crate::data_structs::{self, TrimmedTrack};
+use crate::data_structs;
+use crate::hierarchy::Hierarchy;
+use crate::label_store::{LabelSource, LabelStore};
+
+pub const DB_FILE: &str = "data/track_classification.db";
+pub const SUBLISTS_FILE: &str = "data/sublists.json";
 
 pub async fn write_to_db(
     motherlist: &[data_structs::TrimmedTrack],
-    labels: &[Option<u32>],
+    labels: &[Vec<u32>],
 ) -> Result<()> {
-    let labels_string: Vec<&str> = labels
+    // Labels from `labels::get_labels` start at 1, class ids start at 0. Unlabelled tracks go in
+    // the test split with a placeholder label that is never read.
+    let items: Vec<(&str, TrackClassificationItem)> = motherlist
         .iter()
-        .map(|label| match label {
-            Some(_) => "train",
-            None => "test",
+        .zip(labels.iter())
+        .map(|(track, label)| match label.is_empty() {
+            false => (
+                "train",
+                TrackClassificationItem::new(
+                    track.clone(),
+                    label[0] as usize - 1,
+                    label.iter().map(|x| *x as usize - 1).collect(),
+                ),
+            ),
+            true => (
+                "test",
+                TrackClassificationItem::new(track.clone(), 0, vec![]),
+            ),
         })
         .collect();
-    let items: Vec<(&str, &TrimmedTrack)> = motherlist
-        .iter()
-        .enumerate()
-        .map(|(i, x)| (labels_string[i], x))
-        .collect();
-    let dataset = SqliteDatasetStorage::from_name("data/track_classification.db")
-        .with_base_dir(Path::new("./"));
+    write_items(&items)
+}
+
+/// Moves every track with a manual label in the store into the training split with those labels,
+/// so labels given outside a labelling session, like hand edits of playlists, are trained on.
+/// Sublists a track was excluded from aren't written, see [`crate::label_store::LabelEntry`].
+pub fn sync_manual_labels(store: &LabelStore) -> Result<()> {
+    relabel(store, false)
+}
+
+/// Recomputes the class ids of every track from the manual labels in the store and the current
+/// sublists, moving tracks that lost their last label to the unlabelled split. Needed whenever
+/// sublists are removed or reordered, since class ids are positions in the sublists file.
+pub fn rebuild_labels(store: &LabelStore) -> Result<()> {
+    relabel(store, true)
+}
+
+fn relabel(store: &LabelStore, unlabel_missing: bool) -> Result<()> {
+    let sublists = read_sublists()?;
+    let mut items: Vec<(&str, TrackClassificationItem)> = Vec::new();
+    for split in ["train", "test"] {
+        items.extend(read_split(split)?.into_iter().map(|item| (split, item)));
+    }
+
+    items.iter_mut().for_each(|(split, item)| {
+        let labels: Vec<usize> = store
+            .get(&item.track.track_id, LabelSource::Manual)
+            .map(|entry| {
+                entry
+                    .sublists
+                    .iter()
+                    .filter_map(|name| sublists.iter().position(|x| x == name))
+                    .collect()
+            })
+            .unwrap_or_default();
+        match labels.first() {
+            Some(label) => {
+                *split = "train";
+                item.label = *label;
+                item.labels = labels;
+            }
+            None if unlabel_missing => {
+                *split = "test";
+                item.label = 0;
+                item.labels = vec![];
+            }
+            None => {}
+        }
+    });
+    write_items(&items)
+}
+
+// Overwrites the database with the given (split, item) pairs
+fn write_items(items: &[(&str, TrackClassificationItem)]) -> Result<()> {
+    let dataset = SqliteDatasetStorage::from_name(DB_FILE).with_base_dir(Path::new("./"));
     // TODO: Find a better way to remove songs that were removed from liked songs than overwriting
     // the dataset and re-adding all songs.
     let mut writer = dataset
@@ -32,8 +102,8 @@ pub async fn write_to_db(
         .context("Error in opening database writer")?;
 
     items
-        .into_iter()
-        .map(|item| writer.write(item.0, item.1))
+        .iter()
+        .map(|item| writer.write(item.0, &item.1))
         .collect::<Result<Vec<usize>, SqliteDatasetError>>()
         .context("Error in writing to database")?;
     writer
@@ -42,13 +112,138 @@ pub async fn write_to_db(
     Ok(())
 }
 
+// Every item of a split of the database. Databases written before tracks had ids can't be
+// matched with the label store, so they have to be rebuilt rather than read with empty ids.
+fn read_split(split: &str) -> Result<Vec<TrackClassificationItem>> {
+    let dataset: SqliteDataset<TrackClassificationItem> =
+        SqliteDataset::from_db_file(DB_FILE, split)
+            .with_context(|| format!("Error in opening {split} split of database"))?;
+    let items: Vec<TrackClassificationItem> = dataset.iter().collect();
+    if items.iter().any(|item| item.track.track_id.is_empty()) {
+        bail!(
+            "{DB_FILE} was written by an older version without track ids, run the label \
+             command again to rebuild it"
+        );
+    }
+    Ok(items)
+}
+
+/// Loads the tracks that weren't given a label, the ones a trained model should classify.
+pub fn unlabelled_tracks() -> Result<Vec<data_structs::TrimmedTrack>> {
+    Ok(read_split("test")?
+        .into_iter()
+        .map(|item| item.track)
+        .collect())
+}
+
+/// Every track in the database, labelled or not.
+pub fn all_tracks() -> Result<Vec<data_structs::TrimmedTrack>> {
+    let mut tracks = Vec::new();
+    for split in ["train", "test"] {
+        tracks.extend(read_split(split)?.into_iter().map(|item| item.track));
+    }
+    Ok(tracks)
+}
+
+/// Readable "track — artists — album" names of every track in the database, by track id.
+pub fn track_names() -> Result<HashMap<String, String>> {
+    Ok(all_tracks()?
+        .into_iter()
+        .map(|track| (track.track_id.clone(), track.text_description()))
+        .collect())
+}
+
+pub fn write_sublists(sublists: &[String]) -> Result<()> {
+    let file = File::create(SUBLISTS_FILE).context("Error in creating sublists file")?;
+    serde_json::to_writer_pretty(BufWriter::new(file), sublists)
+        .context("Error in writing sublists")?;
+    Ok(())
+}
+
+pub fn read_sublists() -> Result<Vec<String>> {
+    let file = File::open(SUBLISTS_FILE).context("Error in opening sublists file")?;
+    serde_json::from_reader(BufReader::new(file)).context("Error in reading sublists")
+}
+
 #[derive(new, Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct TrackClassificationItem {
     pub track: data_structs::TrimmedTrack, // The text for classification
     pub label: usize,                      // The label of the text (classification category)
+    pub labels: Vec<usize>,                // Every label of the text, for multi-label sublists
 }
 
 pub trait TrackClassificationDataset: Dataset<TrackClassificationItem> {
     fn number_of_classes(&self) -> usize;
     fn class_name(&self, class_id: usize) -> String;
 }
+
+/// The labelled tracks of the database together with the names of the sublists they belong to.
+pub struct SublistDataset {
+    items: Vec<TrackClassificationItem>,
+    sublists: Vec<String>,
+}
+
+impl SublistDataset {
+    /// Loads every labelled track written by [`write_to_db`]. With nested sublists a track also
+    /// counts as labelled with every ancestor of its labels, so the model learns the whole path.
+    /// Training always uses a multi-label model for nested sublists.
+    pub fn labelled() -> Result<Self> {
+        let sublists = read_sublists()?;
+        let hierarchy = Hierarchy::load()?;
+        Ok(Self {
+            items: read_split("train")?
+                .into_iter()
+                .map(|mut item| {
+                    item.labels = hierarchy.with_ancestors(&sublists, &item.labels);
+                    item
+                })
+                .collect(),
+            sublists,
+        })
+    }
+
+    /// Whether any track is in more than one sublist, which only a multi-label model can learn.
+    pub fn has_multiple_labels(&self) -> bool {
+        self.items.iter().any(|item| item.labels.len() > 1)
+    }
+
+    /// Keeps only the tracks in `track_ids`, with their current labels.
+    pub fn only(mut self, track_ids: &[String]) -> Self {
+        self.items
+            .retain(|item| track_ids.contains(&item.track.track_id));
+        self
+    }
+
+    /// Shuffles the items and splits off `valid_ratio` of them as a validation set.
+    pub fn split(mut self, valid_ratio: f64, seed: u64) -> (Self, Self) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        self.items.shuffle(&mut rng);
+        let valid_len = (self.items.len() as f64 * valid_ratio).round() as usize;
+        let valid_items = self.items.split_off(self.items.len() - valid_len);
+        let valid = Self {
+            items: valid_items,
+            sublists: self.sublists.clone(),
+        };
+        (self, valid)
+    }
+}
+
+impl Dataset<TrackClassificationItem> for SublistDataset {
+    fn get(&self, index: usize) -> Option<TrackClassificationItem> {
+        self.items.get(index).cloned()
+    }
+
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+}
+
+impl TrackClassificationDataset for SublistDataset {
+    fn number_of_classes(&self) -> usize {
+        self.sublists.len()
+    }
+
+    fn class_name(&self, class_id: usize) -> String {
+        self.sublists[class_id].clone()
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+pub const HIERARCHY_FILE: &str = "data/hierarchy.json";
+
+/// Separates the levels of a sublist path typed by the user, like "Electronic > House".
+pub const PATH_SEPARATOR: &str = ">";
+
+/// Nesting of the sublists, like "Electronic → House → Deep House". Every node is a sublist of its
+/// own with a class id, a label and a playlist, so the sublists stay a flat list and the tree is
+/// kept next to it as the parent of every nested sublist. Top level sublists have no entry.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Hierarchy {
+    parents: BTreeMap<String, String>,
+}
+
+impl Hierarchy {
+    /// Loads the hierarchy of the sublists, flat if none has been saved.
+    pub fn load() -> Result<Self> {
+        Self::load_from(Path::new(HIERARCHY_FILE))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        self.save_to(Path::new(HIERARCHY_FILE))
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let file = File::open(path).context("Error in opening sublist hierarchy")?;
+        serde_json::from_reader(BufReader::new(file)).context("Error in reading sublist hierarchy")
+    }
+
+    pub fn save_to(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).context("Error in creating sublist hierarchy")?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)
+            .context("Error in writing sublist hierarchy")
+    }
+
+    pub fn is_flat(&self) -> bool {
+        self.parents.is_empty()
+    }
+
+    pub fn parent(&self, sublist: &str) -> Option<&str> {
+        self.parents.get(sublist).map(|x| x.as_str())
+    }
+
+    /// Parent first, up to the top level sublist.
+    pub fn ancestors(&self, sublist: &str) -> Vec<&str> {
+        let mut ancestors = vec![];
+        let mut current = sublist;
+        while let Some(parent) = self.parent(current) {
+            ancestors.push(parent);
+            current = parent;
+        }
+        ancestors
+    }
+
+    pub fn children<'a>(&'a self, sublist: &'a str) -> impl Iterator<Item = &'a str> {
+        self.parents
+            .iter()
+            .filter(move |(_, parent)| *parent == sublist)
+            .map(|(child, _)| child.as_str())
+    }
+
+    /// Every sublist nested somewhere below `sublist`.
+    pub fn descendants(&self, sublist: &str) -> Vec<&str> {
+        let mut descendants: Vec<&str> = self.children(sublist).collect();
+        let mut i = 0;
+        while i < descendants.len() {
+            descendants.extend(self.children(descendants[i]));
+            i += 1;
+        }
+        descendants
+    }
+
+    /// Nests `sublist` under `parent`, or moves it to the top level when `parent` is `None`.
+    pub fn set_parent(&mut self, sublist: &str, parent: Option<&str>) -> Result<()> {
+        match parent {
+            Some(parent) => {
+                if parent == sublist || self.ancestors(parent).contains(&sublist) {
+                    bail!("{sublist:?} can't be nested under itself or one of its descendants");
+                }
+                self.parents.insert(sublist.to_string(), parent.to_string());
+            }
+            None => {
+                self.parents.remove(sublist);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) {
+        if let Some(parent) = self.parents.remove(from) {
+            self.parents.insert(to.to_string(), parent);
+        }
+        self.parents
+            .values_mut()
+            .filter(|x| x.as_str() == from)
+            .for_each(|x| *x = to.to_string());
+    }
+
+    /// Takes a sublist out of the tree, its children move up to its parent.
+    pub fn remove(&mut self, sublist: &str) {
+        match self.parents.remove(sublist) {
+            Some(parent) => self
+                .parents
+                .values_mut()
+                .filter(|x| x.as_str() == sublist)
+                .for_each(|x| *x = parent.clone()),
+            None => self.parents.retain(|_, x| x != sublist),
+        }
+    }
+
+    /// Drops sublists that no longer exist, so the tree always matches the sublists file.
+    pub fn retain(&mut self, sublists: &[String]) {
+        self.parents
+            .retain(|child, parent| sublists.contains(child) && sublists.contains(parent));
+    }
+
+    /// Adds the ancestors of every class to `classes`, a track in "Deep House" is in "House" too.
+    /// Class ids are positions in `sublists`.
+    pub fn with_ancestors(&self, sublists: &[String], classes: &[usize]) -> Vec<usize> {
+        let mut expanded = classes.to_vec();
+        classes.iter().for_each(|class| {
+            self.ancestors(&sublists[*class])
+                .into_iter()
+                .filter_map(|x| sublists.iter().position(|name| name == x))
+                .for_each(|x| {
+                    if !expanded.contains(&x) {
+                        expanded.push(x)
+                    }
+                })
+        });
+        expanded
+    }
+
+    /// Caps the probability of every sublist at the one of its parent, so independent scores
+    /// never put a track deeper in the tree than it is sure about higher up.
+    pub fn constrain(&self, sublists: &[String], probabilities: &mut [f32]) {
+        // Parents before children, so caps pass all the way down
+        let mut order: Vec<usize> = (0..sublists.len()).collect();
+        order.sort_by_key(|x| self.ancestors(&sublists[*x]).len());
+        for class in order {
+            let parent = self
+                .parent(&sublists[class])
+                .and_then(|x| sublists.iter().position(|name| name == x));
+            if let Some(parent) = parent {
+                probabilities[class] = probabilities[class].min(probabilities[parent]);
+            }
+        }
+    }
+
+    /// Drops classes whose parent isn't among `classes`, since a track can't be in a sublist
+    /// without being in the one it is nested in.
+    pub fn prune(&self, sublists: &[String], classes: &mut Vec<usize>) {
+        loop {
+            let before = classes.len();
+            let kept: Vec<usize> = classes
+                .iter()
+                .cloned()
+                .filter(|class| match self.parent(&sublists[*class]) {
+                    Some(parent) => classes.iter().any(|x| sublists[*x] == parent),
+                    None => true,
+                })
+                .collect();
+            *classes = kept;
+            if classes.len() == before {
+                break;
+            }
+        }
+    }
+
+    /// The sublists depth first, each with its depth, for printing the tree.
+    pub fn tree<'a>(&self, sublists: &'a [String]) -> Vec<(usize, &'a String)> {
+        let mut tree = vec![];
+        let mut stack: Vec<(usize, &String)> = sublists
+            .iter()
+            .rev()
+            .filter(|x| self.parent(x).is_none())
+            .map(|x| (0, x))
+            .collect();
+        while let Some((depth, sublist)) = stack.pop() {
+            tree.push((depth, sublist));
+            stack.extend(
+                sublists
+                    .iter()
+                    .rev()
+                    .filter(|x| self.parent(x) == Some(sublist.as_str()))
+                    .map(|x| (depth + 1, x)),
+            );
+        }
+        tree
+    }
+}
+
+/// Splits a sublist path typed by the user, "Electronic > House" becomes both sublists with the
+/// last one nested in the first.
+pub fn parse_path(input: &str) -> Vec<String> {
+    input
+        .split(PATH_SEPARATOR)
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|x| x.to_string()).collect()
+    }
+
+    // Electronic > House > Deep House, Electronic > Techno, and Jazz on its own
+    fn hierarchy() -> Hierarchy {
+        let mut hierarchy = Hierarchy::default();
+        hierarchy.set_parent("House", Some("Electronic")).unwrap();
+        hierarchy.set_parent("Deep House", Some("House")).unwrap();
+        hierarchy.set_parent("Techno", Some("Electronic")).unwrap();
+        hierarchy
+    }
+
+    fn sublists() -> Vec<String> {
+        names(&["Electronic", "House", "Deep House", "Techno", "Jazz"])
+    }
+
+    #[test]
+    fn set_parent_rejects_cycles() {
+        let mut hierarchy = hierarchy();
+        let before = hierarchy.clone();
+        assert!(hierarchy
+            .set_parent("Electronic", Some("Electronic"))
+            .is_err());
+        assert!(hierarchy
+            .set_parent("Electronic", Some("Deep House"))
+            .is_err());
+        assert!(hierarchy.set_parent("House", Some("Deep House")).is_err());
+        assert_eq!(hierarchy, before);
+
+        hierarchy
+            .set_parent("Deep House", Some("Electronic"))
+            .unwrap();
+        assert_eq!(hierarchy.ancestors("Deep House"), vec!["Electronic"]);
+        hierarchy.set_parent("House", None).unwrap();
+        assert_eq!(hierarchy.parent("House"), None);
+    }
+
+    #[test]
+    fn remove_moves_children_up() {
+        let mut hierarchy = hierarchy();
+        hierarchy.remove("House");
+        assert_eq!(hierarchy.parent("Deep House"), Some("Electronic"));
+        assert_eq!(hierarchy.parent("House"), None);
+
+        // Children of a top level sublist become top level themselves
+        hierarchy.remove("Electronic");
+        assert!(hierarchy.is_flat());
+    }
+
+    #[test]
+    fn with_ancestors_adds_the_path() {
+        let mut classes = hierarchy().with_ancestors(&sublists(), &[2, 4]);
+        classes.sort();
+        assert_eq!(classes, vec![0, 1, 2, 4]);
+    }
+
+    #[test]
+    fn prune_drops_classes_without_their_parent() {
+        let hierarchy = hierarchy();
+        let mut classes = vec![0, 3, 2, 4];
+        hierarchy.prune(&sublists(), &mut classes);
+        // Deep House is dropped with House missing, Techno stays under Electronic
+        assert_eq!(classes, vec![0, 3, 4]);
+
+        // Dropping a class drops the classes below it too
+        let mut classes = vec![1, 2, 3];
+        hierarchy.prune(&sublists(), &mut classes);
+        assert!(classes.is_empty());
+    }
+
+    #[test]
+    fn constrain_caps_at_the_parent() {
+        let mut probabilities = vec![0.4, 0.9, 0.8, 0.3, 0.7];
+        hierarchy().constrain(&sublists(), &mut probabilities);
+        // Deep House is capped by House after House is capped by Electronic
+        assert_eq!(probabilities, vec![0.4, 0.4, 0.4, 0.3, 0.7]);
+    }
+}
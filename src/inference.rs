@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use burn::{
+    data::dataloader::batcher::Batcher,
+    module::Module,
+    record::CompactRecorder,
+    tensor::{
+        activation::{sigmoid, softmax},
+        backend::Backend,
+        Tensor,
+    },
+};
+use serde::Serialize;
+
+use crate::artifacts::ArtifactDir;
+use crate::batcher::{
+    SegmentSequenceBatcher, SegmentSequenceInferenceBatch, SongClassificationBatcher,
+    TextClassificationInferenceBatch,
+};
+use crate::data_structs::TrimmedTrack;
+use crate::dataset;
+use crate::hierarchy::Hierarchy;
+use crate::label_store::LabelStore;
+use crate::misc_helpers::{truncate, OutputFormat};
+use crate::model::{FusionClassifier, MlpClassifier, SegmentTransformer, TextTransformer};
+use crate::rules::RuleConfig;
+use crate::thresholds::{Thresholds, UNSORTED};
+use crate::tokenizer::{BertCasedTokenizer, Tokenizer};
+use crate::training::ModelKind;
+
+pub enum TrainedModel<B: Backend> {
+    Mlp(MlpClassifier<B>),
+    Sequence(SegmentTransformer<B>),
+    Text(TextTransformer<B>),
+    Fusion(FusionClassifier<B>),
+}
+
+/// A trained model together with everything needed to feed it tracks, loaded from an artifact
+/// directory.
+pub struct Predictor<B: Backend> {
+    model: TrainedModel<B>,
+    song_batcher: SongClassificationBatcher<B>,
+    sequence_batcher: SegmentSequenceBatcher<B>,
+    batch_size: usize,
+    pub class_names: Vec<String>,
+    /// Whether the model scores every sublist independently, so a track can be in several.
+    pub multi_label: bool,
+    pub hierarchy: Hierarchy,
+}
+
+impl<B: Backend> Predictor<B> {
+    pub fn load(artifact_dir: &ArtifactDir, device: B::Device) -> Result<Self> {
+        let config = artifact_dir.load_config()?;
+        let class_names = artifact_dir.load_class_names()?;
+        // Deleting, merging or splitting sublists shifts the classes, so the model would label
+        // tracks with sublists that are gone or have moved
+        if class_names != dataset::read_sublists()? {
+            bail!(
+                "The sublists have changed since the model in {} was trained, train a new one",
+                artifact_dir.path().display()
+            );
+        }
+        let normalizer = Arc::new(artifact_dir.load_normalizer()?);
+        let tokenizer = Arc::new(BertCasedTokenizer::default());
+        let n_classes = class_names.len();
+        let path = artifact_dir.model_path();
+        let recorder = CompactRecorder::new();
+
+        let model = match config.model {
+            ModelKind::Mlp => TrainedModel::Mlp(
+                config
+                    .mlp
+                    .init(n_classes, config.multi_label)
+                    .load_file(path, &recorder)
+                    .context("Error in loading trained model")?
+                    .to_device(&device),
+            ),
+            ModelKind::Sequence => TrainedModel::Sequence(
+                config
+                    .sequence
+                    .init(n_classes, config.multi_label)
+                    .load_file(path, &recorder)
+                    .context("Error in loading trained model")?
+                    .to_device(&device),
+            ),
+            ModelKind::Text => TrainedModel::Text(
+                config
+                    .text
+                    .init(
+                        n_classes,
+                        tokenizer.vocab_size(),
+                        config.max_seq_length,
+                        config.multi_label,
+                    )
+                    .load_file(path, &recorder)
+                    .context("Error in loading trained model")?
+                    .to_device(&device),
+            ),
+            ModelKind::Fusion => TrainedModel::Fusion(
+                config
+                    .fusion
+                    .init(
+                        n_classes,
+                        tokenizer.vocab_size(),
+                        config.fusion_branches(),
+                        config.multi_label,
+                    )?
+                    .load_file(path, &recorder)
+                    .context("Error in loading trained model")?
+                    .to_device(&device),
+            ),
+        };
+
+        Ok(Self {
+            model,
+            song_batcher: SongClassificationBatcher::new(
+                tokenizer,
+                normalizer,
+                device.clone(),
+                config.max_seq_length,
+                n_classes,
+            ),
+            sequence_batcher: SegmentSequenceBatcher::new(
+                device,
+                config.sequence.max_segments,
+                config.sequence.stride,
+                n_classes,
+            ),
+            batch_size: config.batch_size,
+            class_names,
+            multi_label: config.multi_label,
+            hierarchy: artifact_dir.load_hierarchy()?,
+        })
+    }
+
+    /// Returns the probability of every class for each track, in the order of `class_names`. For
+    /// multi-label models these don't sum to 1, each is the chance of the track being in that
+    /// class, never higher than the chance of being in the sublist it is nested in.
+    pub fn predict(&self, tracks: &[TrimmedTrack]) -> Vec<Vec<f32>> {
+        tracks
+            .chunks(self.batch_size.max(1))
+            .flat_map(|chunk| {
+                let logits = self.logits(chunk.to_vec());
+                let probabilities = match self.multi_label {
+                    true => sigmoid(logits),
+                    false => softmax(logits, 1),
+                };
+                probabilities
+                    .into_data()
+                    .convert::<f32>()
+                    .value
+                    .chunks(self.class_names.len())
+                    .map(|x| {
+                        let mut probabilities = x.to_vec();
+                        if self.multi_label && !self.hierarchy.is_flat() {
+                            self.hierarchy
+                                .constrain(&self.class_names, &mut probabilities);
+                        }
+                        probabilities
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// The classes a track is put in given its probabilities, empty when no class is confident
+    /// enough and the track is unsorted.
+    pub fn guess(&self, thresholds: &Thresholds, probabilities: &[f32]) -> Vec<usize> {
+        match self.multi_label {
+            true => {
+                let mut classes = thresholds.assign_all(&self.class_names, probabilities);
+                self.hierarchy.prune(&self.class_names, &mut classes);
+                classes
+            }
+            false => thresholds
+                .assign(&self.class_names, probabilities)
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    fn logits(&self, tracks: Vec<TrimmedTrack>) -> Tensor<B, 2> {
+        match &self.model {
+            TrainedModel::Mlp(model) => {
+                let batch: TextClassificationInferenceBatch<B> = self.song_batcher.batch(tracks);
+                model.forward(batch.features)
+            }
+            TrainedModel::Text(model) => {
+                let batch: TextClassificationInferenceBatch<B> = self.song_batcher.batch(tracks);
+                model.forward(batch.tokens, batch.mask_pad, batch.features)
+            }
+            TrainedModel::Sequence(model) => {
+                let batch: SegmentSequenceInferenceBatch<B> = self.sequence_batcher.batch(tracks);
+                model.forward(batch.segments, batch.mask_pad)
+            }
+            TrainedModel::Fusion(model) => {
+                let sequence: SegmentSequenceInferenceBatch<B> =
+                    self.sequence_batcher.batch(tracks.clone());
+                let song: TextClassificationInferenceBatch<B> = self.song_batcher.batch(tracks);
+                model.forward(
+                    song.tokens,
+                    song.mask_pad,
+                    song.features,
+                    sequence.segments,
+                    sequence.mask_pad,
+                )
+            }
+        }
+    }
+}
+
+/// One classified track as shown to the user.
+#[derive(Debug, Clone, Serialize)]
+pub struct Prediction {
+    pub track_id: String,
+    pub track_name: String,
+    pub artists: Vec<String>,
+    pub sublists: Vec<String>,
+    pub confidence: f32,
+    pub probabilities: BTreeMap<String, f32>,
+}
+
+/// Classifies every unlabelled track with the model in `artifact_dir` and stores the results in
+/// the label store as predicted labels. Tracks for which no sublist clears its threshold, or
+/// whose sublists' rule constraints they don't match, are put in the [`UNSORTED`] bucket.
+pub fn predict_unlabelled<B: Backend>(
+    artifact_dir: &ArtifactDir,
+    thresholds: &Thresholds,
+    device: B::Device,
+) -> Result<Vec<Prediction>> {
+    let predictor = Predictor::<B>::load(artifact_dir, device)?;
+    let tracks = dataset::unlabelled_tracks()?;
+    let probabilities = predictor.predict(&tracks);
+    let rules = RuleConfig::load()?;
+
+    let mut store = LabelStore::load()?;
+    let predictions: Vec<Prediction> = tracks
+        .iter()
+        .zip(probabilities.iter())
+        .map(|(track, probabilities)| {
+            let confidence = probabilities.iter().cloned().fold(0.0, f32::max);
+            let mut classes = predictor.guess(thresholds, probabilities);
+            classes.retain(|class| rules.allows(&predictor.class_names[*class], track));
+            let sublists = match classes.is_empty() {
+                true => vec![UNSORTED.to_string()],
+                false => classes
+                    .iter()
+                    .map(|class| predictor.class_names[*class].clone())
+                    .collect(),
+            };
+            store.record_prediction(
+                &track.track_id,
+                sublists.clone(),
+                &predictor.class_names,
+                probabilities,
+            );
+            Prediction {
+                track_id: track.track_id.clone(),
+                track_name: track.track_name.clone(),
+                artists: track.artists.clone(),
+                sublists,
+                confidence,
+                probabilities: predictor
+                    .class_names
+                    .iter()
+                    .cloned()
+                    .zip(probabilities.iter().cloned())
+                    .collect(),
+            }
+        })
+        .collect();
+    store.save()?;
+    Ok(predictions)
+}
+
+pub fn print_predictions(predictions: &[Prediction], format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Json => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(predictions)
+                    .context("Error in serializing predictions")?
+            );
+        }
+        OutputFormat::Table => {
+            println!(
+                "{:<40} {:<30} {:<20} {:>10}",
+                "Song", "Artists", "Sublists", "Confidence"
+            );
+            predictions.iter().for_each(|x| {
+                println!(
+                    "{:<40} {:<30} {:<20} {:>9.1}%",
+                    truncate(&x.track_name, 40),
+                    truncate(&x.artists.join(", "), 30),
+                    truncate(&x.sublists.join(", "), 20),
+                    x.confidence * 100.0
+                )
+            });
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,276 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::data_structs::TrimmedTrack;
+use crate::label_store::{LabelEntry, LabelSource, LabelStore};
+use crate::thresholds::UNSORTED;
+
+/// One row of an exported label file. A track in several sublists gets one row per sublist so
+/// the file stays flat enough to edit in a spreadsheet, and one more per sublist it was excluded
+/// from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LabelRecord {
+    pub track_id: String,
+    /// Informational only, ignored on import.
+    #[serde(default)]
+    pub track_name: String,
+    /// Informational only, ignored on import.
+    #[serde(default)]
+    pub artists: String,
+    pub sublist: String,
+    pub source: LabelSource,
+    /// The track is known not to be in the sublist, see [`LabelEntry::excluded`].
+    #[serde(default)]
+    pub excluded: bool,
+    /// Unix timestamp, left empty for rows added by hand it becomes the time of the import.
+    #[serde(default)]
+    pub timestamp: Option<i64>,
+}
+
+/// What happened to the rows of an imported file.
+#[derive(Debug, Clone, Default)]
+pub struct ImportReport {
+    /// Number of tracks whose labels were replaced.
+    pub imported: usize,
+    /// Rows whose track isn't in the motherlist, by track id.
+    pub unknown_tracks: Vec<String>,
+    /// Rows whose sublist isn't one of the sublists, by sublist name.
+    pub unknown_sublists: Vec<String>,
+    /// Tracks with a label of a source in the file but no usable row of it, whose label was
+    /// kept as it was, by track id.
+    pub unchanged: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileFormat {
+    Csv,
+    Json,
+}
+
+// The format is picked from the file extension
+fn file_format(path: &Path) -> Result<FileFormat> {
+    match path.extension().and_then(|x| x.to_str()) {
+        Some("csv") => Ok(FileFormat::Csv),
+        Some("json") => Ok(FileFormat::Json),
+        _ => bail!("Label files must end in .csv or .json, got {path:?}"),
+    }
+}
+
+/// Writes every label of the store to a CSV or JSON file.
+pub fn export(store: &LabelStore, tracks: &[TrimmedTrack], path: &Path) -> Result<usize> {
+    let tracks: HashMap<&str, &TrimmedTrack> =
+        tracks.iter().map(|x| (x.track_id.as_str(), x)).collect();
+    let records: Vec<LabelRecord> = store
+        .entries
+        .iter()
+        .flat_map(|entry| {
+            let track = tracks.get(entry.track_id.as_str());
+            let sublists = entry.sublists.iter().map(|x| (x, false));
+            let excluded = entry.excluded.iter().map(|x| (x, true));
+            sublists
+                .chain(excluded)
+                .map(move |(sublist, excluded)| LabelRecord {
+                    track_id: entry.track_id.clone(),
+                    track_name: track.map(|x| x.track_name.clone()).unwrap_or_default(),
+                    artists: track.map(|x| x.artists.join("; ")).unwrap_or_default(),
+                    sublist: sublist.clone(),
+                    source: entry.source,
+                    excluded,
+                    timestamp: Some(entry.timestamp),
+                })
+        })
+        .collect();
+
+    // Checked before creating the file, so a bad extension doesn't leave an empty one behind
+    let format = file_format(path)?;
+    let file = File::create(path).context("Error in creating label file")?;
+    match format {
+        FileFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(BufWriter::new(file));
+            for record in records.iter() {
+                writer
+                    .serialize(record)
+                    .context("Error in writing label row")?;
+            }
+            writer.flush().context("Error in writing label file")?;
+        }
+        FileFormat::Json => serde_json::to_writer_pretty(BufWriter::new(file), &records)
+            .context("Error in writing label file")?,
+    }
+    Ok(records.len())
+}
+
+/// Replaces the labels of every track in the file with the ones in it, per source, including the
+/// sublists it is excluded from. A sublist is taken out of a label by leaving its row out, but
+/// a track needs at least one row of that source left: a track without any, or whose only rows
+/// are left out, keeps its label and is reported as unchanged. Rows with a track outside the
+/// motherlist or a sublist that doesn't exist are reported and left out.
+pub fn import(
+    store: &mut LabelStore,
+    tracks: &[TrimmedTrack],
+    sublists: &[String],
+    path: &Path,
+) -> Result<ImportReport> {
+    let file = File::open(path).context("Error in opening label file")?;
+    let records: Vec<LabelRecord> = match file_format(path)? {
+        FileFormat::Csv => csv::Reader::from_reader(BufReader::new(file))
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .context("Error in reading label file")?,
+        FileFormat::Json => {
+            serde_json::from_reader(BufReader::new(file)).context("Error in reading label file")?
+        }
+    };
+
+    let known_tracks: HashSet<&str> = tracks.iter().map(|x| x.track_id.as_str()).collect();
+    let sources: BTreeSet<LabelSource> = records.iter().map(|x| x.source).collect();
+    let mut report = ImportReport::default();
+    let now = chrono::Utc::now().timestamp();
+    // Sublists and excluded sublists of every label in the file
+    let mut grouped: BTreeMap<(String, LabelSource), (Vec<String>, Vec<String>, i64)> =
+        BTreeMap::new();
+    for record in records {
+        if !known_tracks.contains(record.track_id.as_str()) {
+            report.unknown_tracks.push(record.track_id);
+            continue;
+        }
+        // Only predictions can be unsorted, a manual label always names a sublist
+        let unsorted = record.sublist == UNSORTED
+            && record.source == LabelSource::Predicted
+            && !record.excluded;
+        if !sublists.contains(&record.sublist) && !unsorted {
+            report.unknown_sublists.push(record.sublist);
+            continue;
+        }
+        let (labels, excluded, timestamp) = grouped
+            .entry((record.track_id, record.source))
+            .or_insert((vec![], vec![], now));
+        let names = match record.excluded {
+            true => excluded,
+            false => labels,
+        };
+        if !names.contains(&record.sublist) {
+            names.push(record.sublist);
+        }
+        *timestamp = record.timestamp.unwrap_or(now);
+    }
+
+    report.imported = grouped.len();
+    report.unchanged = store
+        .entries
+        .iter()
+        .filter(|x| sources.contains(&x.source) && known_tracks.contains(x.track_id.as_str()))
+        .filter(|x| !grouped.contains_key(&(x.track_id.clone(), x.source)))
+        .map(|x| x.track_id.clone())
+        .collect();
+    grouped
+        .into_iter()
+        .for_each(|((track_id, source), (labels, excluded, timestamp))| {
+            // Probabilities aren't in the file, keep the ones of the previous prediction
+            let probabilities = store
+                .get(&track_id, source)
+                .and_then(|x| x.probabilities.clone());
+            store.upsert(LabelEntry {
+                track_id,
+                // Being in a sublist wins over a contradicting row excluding it
+                excluded: excluded
+                    .into_iter()
+                    .filter(|x| !labels.contains(x))
+                    .collect(),
+                sublists: labels,
+                source,
+                probabilities,
+                timestamp,
+            })
+        });
+    report.unknown_tracks.sort();
+    report.unknown_tracks.dedup();
+    report.unknown_sublists.sort();
+    report.unknown_sublists.dedup();
+    report.unchanged.sort();
+    report.unchanged.dedup();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_rows_are_reported_and_left_out() {
+        let tracks = vec![
+            TrimmedTrack::fake("t1", "One", &["A"], 0),
+            TrimmedTrack::fake("t2", "Two", &["B"], 0),
+            TrimmedTrack::fake("t3", "Three", &["C"], 0),
+        ];
+        let sublists = vec!["Rock".to_string(), "Jazz".to_string()];
+        let mut store = LabelStore::default();
+        store.set_manual("t1", vec!["Rock".to_string()]);
+        store.set_manual("t2", vec!["Jazz".to_string()]);
+        store.set_manual("t3", vec!["Jazz".to_string()]);
+
+        let path =
+            std::env::temp_dir().join(format!("label_io_unknown_{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "track_id,sublist,source\n\
+             t1,Jazz,manual\n\
+             bogus,Rock,manual\n\
+             t2,Polka,manual\n",
+        )
+        .unwrap();
+        let report = import(&mut store, &tracks, &sublists, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.unknown_tracks, vec!["bogus".to_string()]);
+        assert_eq!(report.unknown_sublists, vec!["Polka".to_string()]);
+        // t2's only row was left out and t3 has none, both keep their labels
+        assert_eq!(report.unchanged, vec!["t2".to_string(), "t3".to_string()]);
+        assert_eq!(store.sublists_of("t1").unwrap(), vec!["Jazz".to_string()]);
+        assert_eq!(store.sublists_of("t2").unwrap(), vec!["Jazz".to_string()]);
+        assert!(store.get("bogus", LabelSource::Manual).is_none());
+    }
+
+    #[test]
+    fn export_rejects_unknown_extensions() {
+        let path = std::env::temp_dir().join(format!("label_io_bad_{}.txt", std::process::id()));
+        assert!(export(&LabelStore::default(), &[], &path).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn excluded_labels_survive_export_and_import() {
+        let tracks = vec![
+            TrimmedTrack::fake("t1", "One", &["A"], 0),
+            TrimmedTrack::fake("t2", "Two", &["B"], 0),
+        ];
+        let sublists = vec!["Rock".to_string(), "Jazz".to_string()];
+        let mut store = LabelStore::default();
+        store.set_manual("t1", vec!["Rock".to_string()]);
+        store.record_manual_edit("t1", "Jazz", false);
+        store.record_manual_edit("t2", "Rock", false);
+
+        for extension in ["csv", "json"] {
+            let path = std::env::temp_dir()
+                .join(format!("label_io_test_{}.{extension}", std::process::id()));
+            assert_eq!(export(&store, &tracks, &path).unwrap(), 3);
+            let mut imported = LabelStore::default();
+            let report = import(&mut imported, &tracks, &sublists, &path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(report.imported, 2);
+            let t1 = imported.get("t1", LabelSource::Manual).unwrap();
+            assert_eq!(t1.sublists, vec!["Rock".to_string()]);
+            assert_eq!(t1.excluded, vec!["Jazz".to_string()]);
+            let t2 = imported.get("t2", LabelSource::Manual).unwrap();
+            assert!(t2.sublists.is_empty());
+            assert_eq!(t2.excluded, vec!["Rock".to_string()]);
+        }
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::data_structs::TrimmedTrack;
+use crate::thresholds::UNSORTED;
+
+pub const LABELS_FILE: &str = "data/labels.json";
+
+/// Where a label came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelSource {
+    /// Given by the user in a labelling session
+    Manual,
+    /// Guessed by a trained model
+    Predicted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LabelEntry {
+    pub track_id: String,
+    /// A track can be in several sublists at once.
+    pub sublists: Vec<String>,
+    /// Sublists the track is known not to belong to, like when it was taken out of the playlist
+    /// by hand. Only apply reads these, to keep the track out of those playlists. Training has no
+    /// use for them, a labelled track already counts as a negative for every sublist it isn't in,
+    /// and a track that was only taken out of playlists has no sublist to learn.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded: Vec<String>,
+    pub source: LabelSource,
+    /// Probability of every sublist, only present for predicted labels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probabilities: Option<BTreeMap<String, f32>>,
+    /// Unix timestamp of when the label was set.
+    pub timestamp: i64,
+}
+
+/// Every label, manual or predicted, keyed by Spotify track id. Each track has at most one label
+/// per source.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LabelStore {
+    pub entries: Vec<LabelEntry>,
+}
+
+impl LabelStore {
+    /// Loads the store, starting an empty one if nothing has been saved yet.
+    pub fn load() -> Result<Self> {
+        if !Path::new(LABELS_FILE).exists() {
+            return Ok(Self::default());
+        }
+        let file = File::open(LABELS_FILE).context("Error in opening label store")?;
+        serde_json::from_reader(BufReader::new(file)).context("Error in reading label store")
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let file = File::create(LABELS_FILE).context("Error in creating label store")?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)
+            .context("Error in writing label store")
+    }
+
+    /// Adds the entry, replacing any label of the same source the track already had.
+    pub fn upsert(&mut self, entry: LabelEntry) {
+        self.entries
+            .retain(|x| !(x.track_id == entry.track_id && x.source == entry.source));
+        self.entries.push(entry);
+    }
+
+    pub fn get(&self, track_id: &str, source: LabelSource) -> Option<&LabelEntry> {
+        self.entries
+            .iter()
+            .find(|x| x.track_id == track_id && x.source == source)
+    }
+
+    /// The sublists a track currently belongs to. A manual label replaces the prediction when it
+    /// is set, so a track with both was only edited by hand in some playlists, see
+    /// [`LabelStore::record_manual_edit`], and the prediction still holds for the other sublists.
+    pub fn sublists_of(&self, track_id: &str) -> Option<Vec<String>> {
+        let manual = self.get(track_id, LabelSource::Manual);
+        let predicted = self.get(track_id, LabelSource::Predicted);
+        let (manual, predicted) = match (manual, predicted) {
+            (None, None) => return None,
+            (Some(manual), None) => return Some(manual.sublists.clone()),
+            (None, Some(predicted)) => return Some(predicted.sublists.clone()),
+            (Some(manual), Some(predicted)) => (manual, predicted),
+        };
+        let mut sublists = manual.sublists.clone();
+        predicted
+            .sublists
+            .iter()
+            .filter(|x| *x != UNSORTED && !manual.excluded.contains(x))
+            .for_each(|x| {
+                if !sublists.contains(x) {
+                    sublists.push(x.clone())
+                }
+            });
+        Some(sublists)
+    }
+
+    /// Records that a track was added to (`added`) or taken out of a sublist's playlist by hand.
+    /// Only this one sublist changes in the manual label, a track without one gets a label of
+    /// just this sublist and its prediction is left as it is for the others.
+    pub fn record_manual_edit(&mut self, track_id: &str, sublist: &str, added: bool) {
+        let (mut sublists, mut excluded) = self
+            .get(track_id, LabelSource::Manual)
+            .map(|entry| (entry.sublists.clone(), entry.excluded.clone()))
+            .unwrap_or_default();
+        sublists.retain(|x| x != sublist);
+        excluded.retain(|x| x != sublist);
+        match added {
+            true => sublists.push(sublist.to_string()),
+            false => excluded.push(sublist.to_string()),
+        }
+
+        self.upsert(LabelEntry {
+            track_id: track_id.to_string(),
+            sublists,
+            excluded,
+            source: LabelSource::Manual,
+            probabilities: None,
+            timestamp: chrono::Utc::now().timestamp(),
+        });
+    }
+
+    /// Track ids of every sublist, according to [`LabelStore::sublists_of`].
+    pub fn assignments(&self) -> BTreeMap<String, BTreeSet<String>> {
+        let track_ids: BTreeSet<&str> = self.entries.iter().map(|x| x.track_id.as_str()).collect();
+        let mut assignments: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        track_ids.into_iter().for_each(|track_id| {
+            self.sublists_of(track_id)
+                .unwrap_or_default()
+                .into_iter()
+                .for_each(|sublist| {
+                    assignments
+                        .entry(sublist)
+                        .or_default()
+                        .insert(track_id.to_string());
+                })
+        });
+        assignments
+    }
+
+    /// Renames a sublist in every label and prediction. Renaming into a sublist that already
+    /// exists merges the two, a track in both keeps the higher probability.
+    pub fn rename_sublist(&mut self, from: &str, to: &str) {
+        let rename = |names: &mut Vec<String>| {
+            names
+                .iter_mut()
+                .filter(|x| x.as_str() == from)
+                .for_each(|x| *x = to.to_string());
+            let mut seen = BTreeSet::new();
+            names.retain(|x| seen.insert(x.clone()));
+        };
+        self.entries.iter_mut().for_each(|entry| {
+            rename(&mut entry.sublists);
+            rename(&mut entry.excluded);
+            // Being in the merged sublist wins over having been excluded from one of its parts
+            entry.excluded.retain(|x| !entry.sublists.contains(x));
+            if let Some(probabilities) = entry.probabilities.as_mut() {
+                if let Some(p) = probabilities.remove(from) {
+                    let merged = probabilities.get(to).map_or(p, |x| x.max(p));
+                    probabilities.insert(to.to_string(), merged);
+                }
+            }
+        });
+    }
+
+    /// Takes a sublist out of every label and prediction and returns the tracks that were
+    /// manually labelled with it. Predictions left without a sublist become unsorted, manual
+    /// labels left empty are dropped so the track counts as unlabelled again.
+    pub fn remove_sublist(&mut self, sublist: &str) -> Vec<String> {
+        let mut removed = Vec::new();
+        self.entries.iter_mut().for_each(|entry| {
+            let before = entry.sublists.len();
+            entry.sublists.retain(|x| x != sublist);
+            entry.excluded.retain(|x| x != sublist);
+            if let Some(probabilities) = entry.probabilities.as_mut() {
+                probabilities.remove(sublist);
+            }
+            match entry.source {
+                LabelSource::Manual if entry.sublists.len() < before => {
+                    removed.push(entry.track_id.clone())
+                }
+                LabelSource::Predicted if entry.sublists.is_empty() => {
+                    entry.sublists.push(UNSORTED.to_string())
+                }
+                _ => {}
+            }
+        });
+        self.entries.retain(|x| {
+            !(x.source == LabelSource::Manual && x.sublists.is_empty() && x.excluded.is_empty())
+        });
+        removed
+    }
+
+    /// Records the labels of a labelling session, replacing the predictions of those tracks.
+    /// `labels` start at 1 like in [`crate::labels::get_labels`].
+    pub fn record_manual(
+        &mut self,
+        motherlist: &[TrimmedTrack],
+        sublists: &[String],
+        labels: &[Vec<u32>],
+    ) {
+        let timestamp = chrono::Utc::now().timestamp();
+        motherlist
+            .iter()
+            .zip(labels.iter())
+            .filter(|(_, label)| !label.is_empty())
+            .for_each(|(track, label)| {
+                self.remove_prediction(&track.track_id);
+                self.upsert(LabelEntry {
+                    track_id: track.track_id.clone(),
+                    sublists: label
+                        .iter()
+                        .map(|x| sublists[*x as usize - 1].clone())
+                        .collect(),
+                    excluded: vec![],
+                    source: LabelSource::Manual,
+                    probabilities: None,
+                    timestamp,
+                })
+            });
+    }
+
+    /// Sets the manual label of a single track, replacing its prediction. The sublists it was
+    /// excluded from are kept unless it is now in them.
+    pub fn set_manual(&mut self, track_id: &str, sublists: Vec<String>) {
+        let excluded = self
+            .get(track_id, LabelSource::Manual)
+            .map(|x| {
+                x.excluded
+                    .iter()
+                    .filter(|sublist| !sublists.contains(sublist))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.remove_prediction(track_id);
+        self.upsert(LabelEntry {
+            track_id: track_id.to_string(),
+            sublists,
+            excluded,
+            source: LabelSource::Manual,
+            probabilities: None,
+            timestamp: chrono::Utc::now().timestamp(),
+        });
+    }
+
+    fn remove_prediction(&mut self, track_id: &str) {
+        self.entries
+            .retain(|x| !(x.track_id == track_id && x.source == LabelSource::Predicted));
+    }
+
+    /// Records a model prediction. `sublists` are the sublists the track was assigned to, which is
+    /// just [`crate::thresholds::UNSORTED`] when no sublist was confident enough.
+    pub fn record_prediction(
+        &mut self,
+        track_id: &str,
+        sublists: Vec<String>,
+        class_names: &[String],
+        probabilities: &[f32],
+    ) {
+        self.upsert(LabelEntry {
+            track_id: track_id.to_string(),
+            sublists,
+            excluded: vec![],
+            source: LabelSource::Predicted,
+            probabilities: Some(
+                class_names
+                    .iter()
+                    .cloned()
+                    .zip(probabilities.iter().cloned())
+                    .collect(),
+            ),
+            timestamp: chrono::Utc::now().timestamp(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn predict(store: &mut LabelStore, track_id: &str, sublists: &[&str], p: &[(&str, f32)]) {
+        let class_names: Vec<String> = p.iter().map(|x| x.0.to_string()).collect();
+        let probabilities: Vec<f32> = p.iter().map(|x| x.1).collect();
+        store.record_prediction(track_id, names(sublists), &class_names, &probabilities);
+    }
+
+    #[test]
+    fn rename_into_existing_sublist_merges() {
+        let mut store = LabelStore::default();
+        store.set_manual("a", names(&["House", "Techno"]));
+        store.set_manual("b", names(&["House"]));
+        store.record_manual_edit("b", "Techno", false);
+        predict(
+            &mut store,
+            "c",
+            &["House", "Techno"],
+            &[("House", 0.9), ("Techno", 0.6), ("Jazz", 0.1)],
+        );
+        predict(
+            &mut store,
+            "d",
+            &["House"],
+            &[("House", 0.3), ("Techno", 0.8), ("Jazz", 0.2)],
+        );
+
+        store.rename_sublist("House", "Techno");
+
+        assert_eq!(store.sublists_of("a").unwrap(), names(&["Techno"]));
+        // Being in the merged sublist wins over having been excluded from a part
+        let b = store.get("b", LabelSource::Manual).unwrap();
+        assert_eq!(b.sublists, names(&["Techno"]));
+        assert!(b.excluded.is_empty());
+
+        let c = store.get("c", LabelSource::Predicted).unwrap();
+        assert_eq!(c.sublists, names(&["Techno"]));
+        let probabilities = c.probabilities.as_ref().unwrap();
+        assert_eq!(probabilities.get("Techno"), Some(&0.9));
+        assert_eq!(probabilities.get("Jazz"), Some(&0.1));
+        assert!(!probabilities.contains_key("House"));
+
+        let d = store.get("d", LabelSource::Predicted).unwrap();
+        assert_eq!(d.probabilities.as_ref().unwrap().get("Techno"), Some(&0.8));
+    }
+
+    #[test]
+    fn remove_sublist_leaves_predictions_unsorted() {
+        let mut store = LabelStore::default();
+        store.set_manual("a", names(&["House"]));
+        store.set_manual("b", names(&["House", "Jazz"]));
+        store.set_manual("c", names(&["Jazz"]));
+        predict(
+            &mut store,
+            "d",
+            &["House"],
+            &[("House", 0.9), ("Jazz", 0.2)],
+        );
+        predict(
+            &mut store,
+            "e",
+            &["House", "Jazz"],
+            &[("House", 0.9), ("Jazz", 0.7)],
+        );
+
+        let removed = store.remove_sublist("House");
+
+        assert_eq!(removed, names(&["a", "b"]));
+        // A manual label left empty is dropped, the track is unlabelled again
+        assert!(store.get("a", LabelSource::Manual).is_none());
+        assert_eq!(store.sublists_of("b").unwrap(), names(&["Jazz"]));
+        assert_eq!(store.sublists_of("c").unwrap(), names(&["Jazz"]));
+
+        let d = store.get("d", LabelSource::Predicted).unwrap();
+        assert_eq!(d.sublists, names(&[UNSORTED]));
+        assert!(!d.probabilities.as_ref().unwrap().contains_key("House"));
+        assert_eq!(store.sublists_of("e").unwrap(), names(&["Jazz"]));
+    }
+
+    #[test]
+    fn hand_edits_leave_the_prediction_alone() {
+        let mut store = LabelStore::default();
+        predict(
+            &mut store,
+            "a",
+            &["House", "Jazz"],
+            &[("House", 0.9), ("Jazz", 0.8)],
+        );
+
+        store.record_manual_edit("a", "Jazz", false);
+        let manual = store.get("a", LabelSource::Manual).unwrap();
+        assert!(manual.sublists.is_empty());
+        assert_eq!(manual.excluded, names(&["Jazz"]));
+        assert_eq!(store.sublists_of("a").unwrap(), names(&["House"]));
+
+        store.record_manual_edit("a", "Techno", true);
+        assert_eq!(
+            store.get("a", LabelSource::Manual).unwrap().sublists,
+            names(&["Techno"])
+        );
+        assert_eq!(
+            store.get("a", LabelSource::Predicted).unwrap().sublists,
+            names(&["House", "Jazz"])
+        );
+        assert_eq!(store.sublists_of("a").unwrap(), names(&["Techno", "House"]));
+
+        // A full manual label replaces the prediction
+        store.set_manual("a", names(&["Jazz"]));
+        assert!(store.get("a", LabelSource::Predicted).is_none());
+        assert_eq!(store.sublists_of("a").unwrap(), names(&["Jazz"]));
+    }
+
+    #[test]
+    fn remove_sublist_keeps_other_exclusions() {
+        let mut store = LabelStore::default();
+        store.record_manual_edit("a", "House", false);
+        store.record_manual_edit("a", "Jazz", false);
+
+        assert!(store.remove_sublist("House").is_empty());
+        let a = store.get("a", LabelSource::Manual).unwrap();
+        assert!(a.sublists.is_empty());
+        assert_eq!(a.excluded, names(&["Jazz"]));
+    }
+}
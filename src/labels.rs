@@ -1,6 +1,13 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Result;
 use rand::seq::SliceRandom;
 
+use crate::client::PlaylistClient;
 use crate::data_structs as data;
+use crate::hierarchy::{parse_path, Hierarchy, PATH_SEPARATOR};
+use crate::label_store::{LabelSource, LabelStore};
+use crate::session::LabelSession;
 
 // pub async fn create_db(
 //     motherlist: Vec<data::BetterSavedTrack>,
@@ -14,13 +21,15 @@ use crate::data_structs as data;
 //     .await;
 // }
 
-pub async fn get_sublists() -> Vec<String> {
-    println!("We will now create the subplaylists from the parent playlist. Currently only allows for mutually exclusive sublists.");
+/// Asks for the sublist names. A name can be a path like "Electronic > House > Deep House" to
+/// nest sublists, every level becomes a sublist of its own.
+pub async fn get_sublists() -> (Vec<String>, Hierarchy) {
+    println!("We will now create the subplaylists from the parent playlist. A song can be put in more than one subplaylist.");
 
     let mut sublists = vec![];
+    let mut hierarchy = Hierarchy::default();
 
-    println!("Input the name of a subplaylist. Note that there is no check for if you have
-        already created a subplaylist with the same name. Input 0 if you are done creating subplaylists.");
+    println!("Input the name of a subplaylist, or a path like \"Electronic {PATH_SEPARATOR} House\" to nest it in another one. Input 0 if you are done creating subplaylists.");
     loop {
         let mut input = String::new();
         std::io::stdin()
@@ -29,30 +38,81 @@ pub async fn get_sublists() -> Vec<String> {
 
         match input.trim() {
             "0" => break,
-            sublist_name => {
-                if sublists.contains(&sublist_name.to_string()) {
-                    println!("Sublists already contains {:?}", sublist_name);
+            input => {
+                let path = parse_path(input);
+                let Some(sublist_name) = path.last() else {
                     continue;
-                } else {
-                    sublists.push(sublist_name.to_owned());
                 };
+                if sublists.contains(sublist_name) {
+                    println!("Sublists already contains {:?}", sublist_name);
+                    continue;
+                }
+                for (i, name) in path.iter().enumerate() {
+                    if !sublists.contains(name) {
+                        sublists.push(name.clone());
+                    }
+                    if let Some(parent) = i.checked_sub(1).map(|x| path[x].as_str()) {
+                        if let Err(error) = hierarchy.set_parent(name, Some(parent)) {
+                            println!("{error}");
+                        }
+                    }
+                }
             }
         };
 
         println!("Current subplaylists:");
-        sublists.iter().for_each(|x| print!("{:?}, ", x));
-        println!("\n");
+        hierarchy
+            .tree(&sublists)
+            .iter()
+            .for_each(|(depth, x)| println!("{}{x}", "  ".repeat(*depth)));
+        println!();
     }
-    sublists
+    (sublists, hierarchy)
 }
 
-pub async fn get_labels(
+/// Model predictions that steer a labelling session, see [`crate::active_learning`].
+pub struct Guidance {
+    /// Motherlist indices in the order they should be presented.
+    pub order: Vec<usize>,
+    /// The sublists the model would put each motherlist track in, numbered from 1 like labels.
+    pub guesses: Vec<Vec<u32>>,
+}
+
+/// The manual labels the store already has for the motherlist, numbered from 1 like the labels
+/// of [`get_labels`]. Labels of sublists that no longer exist are dropped.
+pub fn stored_labels(
+    store: &LabelStore,
     sublists: &[String],
-    motherlist: &[data::BetterSavedTrack],
-) -> Vec<Option<u32>> {
-    let mut labels: Vec<Option<u32>> = vec![None; motherlist.len()];
+    motherlist: &[data::TrimmedTrack],
+) -> Vec<Vec<u32>> {
+    motherlist
+        .iter()
+        .map(
+            |track| match store.get(&track.track_id, LabelSource::Manual) {
+                Some(entry) => entry
+                    .sublists
+                    .iter()
+                    .filter_map(|name| sublists.iter().position(|x| x == name))
+                    .map(|x| x as u32 + 1)
+                    .collect(),
+                None => vec![],
+            },
+        )
+        .collect()
+}
 
-    println!("Now printing songs from the motherlist. For each song, please categorize the song into one of your provided subplaylists by typing the number corresponding to the selected subplaylist. This allows us to create seeds for the subplaylists, the more songs you categorize now will result in more accurate subplaylists. Input 0 when you are done seeding songs.");
+/// Asks the user to label the tracks of the motherlist that aren't in `labels` yet. Tracks come
+/// in random order, or in the order of `guidance` with the model's guess accepted by pressing
+/// enter. Besides labelling, tracks can be skipped, labels undone, any track found by search and
+/// relabelled. A `resumable` session is saved as it goes so it can be resumed later.
+pub async fn get_labels(
+    sublists: &[String],
+    motherlist: &[data::TrimmedTrack],
+    mut labels: Vec<Vec<u32>>,
+    guidance: Option<Guidance>,
+    resumable: bool,
+) -> Result<Vec<Vec<u32>>> {
+    println!("Now printing songs from the motherlist. For each song, please categorize the song into your provided subplaylists by typing the numbers corresponding to the selected subplaylists, separated by spaces if the song belongs in several. This allows us to create seeds for the subplaylists, the more songs you categorize now will result in more accurate subplaylists. Input 0 when you are done seeding songs, or h for the other commands.");
 
     println!("0 break");
     sublists
@@ -60,59 +120,405 @@ pub async fn get_labels(
         .enumerate()
         .for_each(|(i, x)| println!("{} {:?}", i + 1, x));
 
-    let mut rng = rand::thread_rng();
-    let mut randomized_motherlist: Vec<(usize, String, Vec<rspotify::model::SimplifiedArtist>)> =
-        motherlist
-            .iter()
-            .enumerate()
-            .map(|(i, x)| (i, x.track.name.clone(), x.track.artists.clone()))
-            .collect::<Vec<(usize, String, Vec<rspotify::model::SimplifiedArtist>)>>();
-    randomized_motherlist.shuffle(&mut rng);
-    let mut randomized_motherlist = randomized_motherlist.into_iter();
-    let mut past_indices: Vec<usize> = vec![];
-
-    'outer: loop {
-        let (index, track, artists) = match randomized_motherlist.next() {
-            Some(x) => x,
-            None => {
-                println!("No more tracks in motherlist");
-                break;
+    let (mut session, guesses) =
+        start_session(sublists, motherlist, &mut labels, guidance, resumable)?;
+    let index_of = index_of(motherlist);
+
+    while let Some(index) = session.current().map(|x| index_of[x.as_str()]) {
+        let track = &motherlist[index];
+        let guess = &guesses[index];
+
+        println!(
+            "Song: {:?} \t Artists: {:?}\n Input corresponding subplaylist numbers:",
+            track.track_name, track.artists
+        );
+        if !labels[index].is_empty() {
+            println!(
+                "Currently labelled: {:?}",
+                sublist_names(sublists, &labels[index])
+            );
+        }
+        if !guess.is_empty() {
+            println!(
+                "Model guess: {:?}, press enter to accept",
+                sublist_names(sublists, guess)
+            );
+        }
+
+        let mut input = String::new();
+        std::io::stdin()
+            .read_line(&mut input)
+            .expect("Invalid input for subplaylist number/index");
+        match parse_session_input(&input, sublists.len()) {
+            SessionInput::Labels(x) => {
+                session.label(std::mem::replace(&mut labels[index], x.clone()), x);
             }
-        };
-        let artists: Vec<String> = artists.into_iter().map(|x| x.name).collect();
+            SessionInput::Accept if !guess.is_empty() => {
+                session.label(
+                    std::mem::replace(&mut labels[index], guess.clone()),
+                    guess.clone(),
+                );
+            }
+            SessionInput::Skip => session.skip(),
+            SessionInput::Undo => match session.undo() {
+                Some((track_id, previous)) => labels[index_of[track_id.as_str()]] = previous,
+                None => println!("Nothing to undo."),
+            },
+            SessionInput::Search(query) => {
+                if let Some(found) = search(motherlist, &labels, sublists, &query)? {
+                    session.jump_to(&motherlist[found].track_id);
+                }
+            }
+            SessionInput::Stats => print_stats(sublists, &labels, session.remaining()),
+            SessionInput::Quit => {
+                session.save()?;
+                return Ok(labels);
+            }
+            SessionInput::Help | SessionInput::Accept => print_help(),
+            SessionInput::Invalid => {
+                println!("Please enter numbers corresponding to the subplaylists, or h for help.")
+            }
+        }
+        session.save()?;
+    }
+    println!("No more tracks in motherlist");
+    session.discard()?;
+    Ok(labels)
+}
+
+/// Resumes the saved labelling session if the user wants to, applying its labels to `labels`, or
+/// starts a new one. A session that isn't `resumable` never touches the saved one. Also returns
+/// the model's guess for every track, empty without guidance.
+pub fn start_session(
+    sublists: &[String],
+    motherlist: &[data::TrimmedTrack],
+    labels: &mut [Vec<u32>],
+    guidance: Option<Guidance>,
+    resumable: bool,
+) -> Result<(LabelSession, Vec<Vec<u32>>)> {
+    let index_of = index_of(motherlist);
+    let (order, guesses) = match guidance {
+        Some(guidance) => (guidance.order, guidance.guesses),
+        None => {
+            let mut rng = rand::thread_rng();
+            let mut randomized_motherlist: Vec<usize> = (0..motherlist.len())
+                .filter(|i| labels[*i].is_empty())
+                .collect();
+            randomized_motherlist.shuffle(&mut rng);
+            (randomized_motherlist, vec![vec![]; motherlist.len()])
+        }
+    };
+
+    let queue: Vec<String> = order
+        .iter()
+        .map(|i| motherlist[*i].track_id.clone())
+        .collect();
+    if !resumable {
+        return Ok((LabelSession::unsaved(sublists, queue), guesses));
+    }
+    let session = match LabelSession::load(sublists)? {
+        Some(mut session)
+            if ask_yes_no("An unfinished labelling session was found, resume it? [y/n]")? =>
+        {
+            session.retain_tracks(|x| index_of.contains_key(x));
+            session
+                .labels()
+                .iter()
+                .for_each(|(track_id, label)| labels[index_of[track_id.as_str()]] = label.clone());
+            session
+        }
+        _ => LabelSession::new(sublists, queue),
+    };
+    Ok((session, guesses))
+}
+
+pub fn index_of(motherlist: &[data::TrimmedTrack]) -> HashMap<&str, usize> {
+    motherlist
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.track_id.as_str(), i))
+        .collect()
+}
+
+// What the user can type at the labelling prompt
+#[derive(Debug, PartialEq)]
+enum SessionInput {
+    Labels(Vec<u32>),
+    Accept,
+    Skip,
+    Undo,
+    Search(String),
+    Stats,
+    Help,
+    Quit,
+    Invalid,
+}
+
+fn parse_session_input(input: &str, sublists_len: usize) -> SessionInput {
+    match input.trim() {
+        "" => SessionInput::Accept,
+        "s" => SessionInput::Skip,
+        "u" => SessionInput::Undo,
+        "?" => SessionInput::Stats,
+        "h" => SessionInput::Help,
+        "q" => SessionInput::Quit,
+        x if x.starts_with('/') => SessionInput::Search(x[1..].trim().to_lowercase()),
+        x => match parse_subplaylist_indices(x, sublists_len) {
+            Some(x) if x.contains(&0) => SessionInput::Quit,
+            Some(x) => SessionInput::Labels(x),
+            None => SessionInput::Invalid,
+        },
+    }
+}
+
+fn print_help() {
+    println!("1 2 ...   put the song in these subplaylists");
+    println!("enter     accept the model guess");
+    println!("s         skip the song, it comes back at the end");
+    println!("u         undo the last label");
+    println!("/text     find a song by name or artist to label or relabel it");
+    println!("?         show how many songs each subplaylist has");
+    println!("0 or q    stop, the session can be resumed next time");
+}
+
+fn print_stats(sublists: &[String], labels: &[Vec<u32>], remaining: usize) {
+    let labelled = labels.iter().filter(|x| !x.is_empty()).count();
+    println!(
+        "{labelled} of {} songs labelled, {remaining} left in this session",
+        labels.len()
+    );
+    sublists.iter().enumerate().for_each(|(i, name)| {
+        let count = labels
+            .iter()
+            .filter(|x| x.contains(&(i as u32 + 1)))
+            .count();
+        println!("{count:>6} {name}");
+    });
+}
+
+// Lists the songs matching `query` and lets the user pick one, `None` if nothing was picked
+fn search(
+    motherlist: &[data::TrimmedTrack],
+    labels: &[Vec<u32>],
+    sublists: &[String],
+    query: &str,
+) -> Result<Option<usize>> {
+    let matches: Vec<usize> = motherlist
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| {
+            x.track_name.to_lowercase().contains(query)
+                || x.artists.iter().any(|a| a.to_lowercase().contains(query))
+        })
+        .map(|(i, _)| i)
+        .take(10)
+        .collect();
+    if matches.is_empty() {
+        println!("No songs match {query:?}.");
+        return Ok(None);
+    }
+    matches.iter().enumerate().for_each(|(i, x)| {
+        println!(
+            "{} {:?} \t Artists: {:?} \t {:?}",
+            i + 1,
+            motherlist[*x].track_name,
+            motherlist[*x].artists,
+            sublist_names(sublists, &labels[*x])
+        )
+    });
+    println!("Input the number of the song to label, or press enter to go back:");
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    Ok(input
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|x| matches.get(x.checked_sub(1)?))
+        .copied())
+}
+
+pub fn sublist_names<'a>(sublists: &'a [String], labels: &[u32]) -> Vec<&'a String> {
+    labels.iter().map(|x| &sublists[*x as usize - 1]).collect()
+}
+
+fn ask_yes_no(question: &str) -> Result<bool> {
+    println!("{question}");
+    loop {
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+        match input.trim().to_lowercase().as_str() {
+            "y" => return Ok(true),
+            "n" => return Ok(false),
+            _ => println!("Please input either 'y' or 'n'."),
+        }
+    }
+}
+
+/// Creates a sublist for every existing playlist the user picks and labels each motherlist track
+/// with the sublists of the playlists it is in, instead of labelling tracks one at a time. Prints
+/// which tracks ended up in several sublists and asks whether the ones that disagree with their
+/// stored manual label should be relabelled.
+pub async fn seed_from_playlists<C: PlaylistClient>(
+    client: &C,
+    motherlist: &[data::TrimmedTrack],
+    store: &LabelStore,
+) -> Result<(Vec<String>, Vec<Vec<u32>>)> {
+    let playlists = client.user_playlists().await?;
+    println!("Available playlists associated with account:");
+    playlists
+        .iter()
+        .enumerate()
+        .for_each(|(i, x)| println!("{} {:?}", i + 1, x.name));
+    println!("Input the numbers of the playlists to turn into subplaylists, separated by spaces. Every song of the motherlist in one of them is labelled with it.");
 
+    let chosen = loop {
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+        match parse_subplaylist_indices(&input, playlists.len()) {
+            Some(x) if !x.contains(&0) => break x,
+            _ => println!("Please enter numbers corresponding to the playlists."),
+        }
+    };
+
+    let motherlist_ids: HashMap<String, usize> = motherlist
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.track_id.clone(), i))
+        .collect();
+    let mut sublists = Vec::with_capacity(chosen.len());
+    let mut labels: Vec<Vec<u32>> = vec![vec![]; motherlist.len()];
+    for (sublist_index, playlist_index) in chosen.iter().enumerate() {
+        let playlist = &playlists[*playlist_index as usize - 1];
+        let track_ids = client.playlist_track_ids(&playlist.id).await?;
+        let (found, missing): (Vec<&String>, Vec<&String>) = track_ids
+            .iter()
+            .partition(|id| motherlist_ids.contains_key(*id));
+        found.iter().for_each(|id| {
+            let label = &mut labels[motherlist_ids[*id]];
+            // Labels start at 1 like in `get_labels`
+            if !label.contains(&(sublist_index as u32 + 1)) {
+                label.push(sublist_index as u32 + 1);
+            }
+        });
         println!(
-            "Song: {:?} \t Artists: {:?}\n Input corresponding subplaylist number:",
-            track, artists
+            "{:?}: labelled {} songs, {} songs aren't in the motherlist",
+            playlist.name,
+            found.len(),
+            missing.len()
         );
+        sublists.push(playlist.name.clone());
+    }
 
-        let mut subplaylist_index: i32;
-        'inner: loop {
-            let mut subplaylist_index_string = String::new();
-            std::io::stdin()
-                .read_line(&mut subplaylist_index_string)
-                .expect("Invalid input for subplaylist number/index");
-            subplaylist_index = subplaylist_index_string.trim().parse().unwrap_or(-1);
-
-            if subplaylist_index < 0
-                || std::convert::TryInto::<usize>::try_into(subplaylist_index)
-                    .expect("subplaylist_index really shouldn't be greater than max of usize")
-                    > sublists.len()
-            {
-                println!("Please enter a number corresponding to one of the subplaylists.");
-                continue 'inner;
+    // Pairs of sublists that share songs, and songs whose seed disagrees with an earlier label
+    let mut overlaps: BTreeMap<(usize, usize), usize> = BTreeMap::new();
+    labels.iter().for_each(|label| {
+        for (i, a) in label.iter().enumerate() {
+            for b in label.iter().skip(i + 1) {
+                *overlaps
+                    .entry((*a as usize - 1, *b as usize - 1))
+                    .or_default() += 1;
             }
-            break 'inner;
         }
-        if subplaylist_index == 0 {
-            break 'outer;
+    });
+    overlaps.iter().for_each(|((a, b), count)| {
+        println!(
+            "Overlap: {count} songs are in both {:?} and {:?}",
+            sublists[*a], sublists[*b]
+        )
+    });
+    let conflicts: Vec<usize> = motherlist
+        .iter()
+        .zip(labels.iter())
+        .enumerate()
+        .filter(|(_, (_, label))| !label.is_empty())
+        .filter(|(_, (track, label))| {
+            let Some(entry) = store.get(&track.track_id, LabelSource::Manual) else {
+                return false;
+            };
+            let mut seeded: Vec<&String> =
+                label.iter().map(|x| &sublists[*x as usize - 1]).collect();
+            let mut stored: Vec<&String> = entry.sublists.iter().collect();
+            seeded.sort();
+            stored.sort();
+            if seeded != stored {
+                println!(
+                    "Conflict: {:?} was labelled {:?}, seeding labels it {:?}",
+                    track.track_name, stored, seeded
+                );
+            }
+            seeded != stored
+        })
+        .map(|(i, _)| i)
+        .collect();
+    if !conflicts.is_empty()
+        && !ask_yes_no(&format!(
+            "Replace the earlier labels of these {} songs with the seeded ones? (y/n)",
+            conflicts.len()
+        ))?
+    {
+        // Keep what is left of the earlier label among the new sublists, a track without any of
+        // them stays unlabelled here so its stored label isn't overwritten
+        for i in conflicts {
+            if let Some(entry) = store.get(&motherlist[i].track_id, LabelSource::Manual) {
+                labels[i] = entry
+                    .sublists
+                    .iter()
+                    .filter_map(|name| sublists.iter().position(|x| x == name))
+                    .map(|x| x as u32 + 1)
+                    .collect();
+            }
         }
-        let subplaylist_index: u32 = subplaylist_index
-            .try_into()
-            .expect("subplaylist_index really shouldn't be greater than max of u32");
+    }
+    println!(
+        "Apply won't touch the playlists these sublists were seeded from, rename the sublists \
+         before applying to get playlists of their own"
+    );
+
+    Ok((sublists, labels))
+}
+
+// Parses space or comma separated subplaylist numbers, `None` if any of them isn't a valid number
+fn parse_subplaylist_indices(input: &str, sublists_len: usize) -> Option<Vec<u32>> {
+    let mut indices: Vec<u32> = input
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse::<u32>().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    if indices.is_empty() || indices.iter().any(|x| *x as usize > sublists_len) {
+        return None;
+    }
+    indices.sort();
+    indices.dedup();
+    Some(indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_session_input_reads_commands() {
+        assert_eq!(parse_session_input("", 3), SessionInput::Accept);
+        assert_eq!(parse_session_input(" s ", 3), SessionInput::Skip);
+        assert_eq!(parse_session_input("u", 3), SessionInput::Undo);
+        assert_eq!(parse_session_input("?", 3), SessionInput::Stats);
+        assert_eq!(parse_session_input("h", 3), SessionInput::Help);
+        assert_eq!(parse_session_input("q", 3), SessionInput::Quit);
+        assert_eq!(
+            parse_session_input("/ Daft Punk", 3),
+            SessionInput::Search("daft punk".to_string())
+        );
+    }
 
-        labels[index] = Some(subplaylist_index);
-        past_indices.push(index);
+    #[test]
+    fn parse_session_input_reads_labels() {
+        assert_eq!(
+            parse_session_input("3, 1 3", 3),
+            SessionInput::Labels(vec![1, 3])
+        );
+        assert_eq!(parse_session_input("0", 3), SessionInput::Quit);
+        // Out of range or not a number
+        assert_eq!(parse_session_input("4", 3), SessionInput::Invalid);
+        assert_eq!(parse_session_input("x", 3), SessionInput::Invalid);
     }
-    labels
 }
@@ -1,15 +1,222 @@
 pub mod account;
+pub mod active_learning;
+pub mod apply;
+pub mod artifacts;
+pub mod backend;
 pub mod batcher;
+pub mod client;
+pub mod clustering;
 pub mod data_structs;
 pub mod dataset;
+pub mod hierarchy;
+pub mod inference;
+pub mod label_io;
+pub mod label_store;
 pub mod labels;
 pub mod misc_helpers;
+pub mod model;
+pub mod normalizer;
+pub mod outliers;
+pub mod rules;
+pub mod session;
+pub mod sublists;
+pub mod thresholds;
 pub mod tokenizer;
+pub mod training;
+pub mod tui;
 
+use backend::BackendKind;
+use burn::backend::Autodiff;
+use burn::config::Config;
+use burn::tensor::backend::{AutodiffBackend, Backend};
+use clap::{Parser, Subcommand};
 use futures_util::future::join_all;
+use std::path::Path;
 
 use anyhow::{Context, Result};
 
+#[derive(Parser)]
+#[command(about = "Sorts a Spotify playlist into subplaylists with a neural network")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Backend used for training and inference
+    #[arg(long, value_enum, global = true, default_value_t)]
+    backend: BackendKind,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Fetch the motherlist, create sublists and label tracks (the default)
+    Label {
+        /// Turn existing playlists into sublists and label every song in them, instead of
+        /// labelling songs one at a time
+        #[arg(long, conflicts_with_all = ["artifact_dir", "order", "tui"])]
+        seed_from_playlists: bool,
+        /// Training run whose model orders the tracks and suggests their sublists
+        #[arg(long)]
+        artifact_dir: Option<String>,
+        /// Order tracks are presented in, anything but random needs a model
+        #[arg(long, value_enum, requires = "artifact_dir")]
+        order: Option<active_learning::SamplingStrategy>,
+        /// Label in a full-screen terminal UI instead of line by line
+        #[arg(long)]
+        tui: bool,
+    },
+    /// Train a classifier on the labelled tracks
+    Train {
+        /// Directory the model, config and metrics are written to
+        #[arg(long, default_value = "artifacts")]
+        artifact_dir: String,
+        /// JSON training config, defaults are used when omitted
+        #[arg(long)]
+        config: Option<String>,
+        /// Classifier to train, overrides the one in the config
+        #[arg(long, value_enum)]
+        model: Option<training::ModelKind>,
+        /// How features are rescaled before training, overrides the one in the config
+        #[arg(long, value_enum)]
+        normalization: Option<normalizer::NormalizationKind>,
+        /// Train the fusion model once per combination of its branches and report each score
+        #[arg(long)]
+        ablation: bool,
+        /// Continue from the latest checkpoint and config in the artifact directory
+        #[arg(long, conflicts_with_all = ["config", "model", "normalization", "ablation"])]
+        resume: bool,
+    },
+    /// Predict the sublist of every unlabelled track with a trained model
+    Predict {
+        /// Directory of the training run to load the model from
+        #[arg(long, default_value = "artifacts")]
+        artifact_dir: String,
+        #[arg(long, value_enum, default_value_t)]
+        format: misc_helpers::OutputFormat,
+        /// Minimum confidence for every sublist, overrides the tuned thresholds or the default
+        /// of 0.5
+        #[arg(long)]
+        threshold: Option<f32>,
+    },
+    /// Tune per-sublist confidence thresholds on the validation split of a training run
+    TuneThresholds {
+        #[arg(long, default_value = "artifacts")]
+        artifact_dir: String,
+        /// Precision every sublist should reach, lower confidence predictions become unsorted
+        #[arg(long, default_value_t = 0.9)]
+        target_precision: f32,
+    },
+    /// Create and update a Spotify playlist per sublist to match the stored labels
+    Apply {
+        /// Create missing playlists as public instead of private
+        #[arg(long)]
+        public: bool,
+        /// Also keep a playlist of the tracks no sublist was confident enough about
+        #[arg(long)]
+        include_unsorted: bool,
+        /// Also put the tracks of nested sublists in the playlists of the sublists above them
+        #[arg(long)]
+        include_descendants: bool,
+        /// Only print what would change, without touching any playlist
+        #[arg(long)]
+        dry_run: bool,
+        /// Refuse to go on, and exit with an error, if more tracks than this would be added or
+        /// removed in total
+        #[arg(long, default_value_t = 500)]
+        max_changes: usize,
+        #[arg(long, value_enum, default_value_t)]
+        format: misc_helpers::OutputFormat,
+    },
+    /// Accept or correct predicted sublists in a terminal UI, least confident first
+    Review,
+    /// Rename, delete, merge or split sublists, keeping labels and playlists in line
+    Sublists {
+        #[command(subcommand)]
+        action: SublistAction,
+    },
+    /// Manage sublists defined by rules and the rules predictions have to match
+    Rules {
+        #[command(subcommand)]
+        action: RuleAction,
+    },
+    /// Cluster the motherlist to suggest sublists, named clusters become new sublists
+    Discover {
+        #[arg(long, value_enum, default_value_t)]
+        method: clustering::ClusterMethod,
+        /// Fewest clusters to try
+        #[arg(long, default_value_t = 2)]
+        min_clusters: usize,
+        /// Most clusters to try
+        #[arg(long, default_value_t = 12)]
+        max_clusters: usize,
+        /// Tracks shown per cluster, the ones closest to its center. Naming a cluster starts a
+        /// labelling session over them for its new sublist.
+        #[arg(long, default_value_t = 5)]
+        representatives: usize,
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+    },
+    /// List the tracks of every sublist that are least like the rest of it
+    Outliers {
+        #[arg(long, value_enum, default_value_t)]
+        method: outliers::OutlierMethod,
+        /// Training run to score tracks with, needed by the confidence method
+        #[arg(long, required_if_eq("method", "confidence"))]
+        artifact_dir: Option<String>,
+        /// Tracks listed per sublist
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+        #[arg(long, value_enum, default_value_t)]
+        format: misc_helpers::OutputFormat,
+    },
+    /// Write every stored label to a .csv or .json file
+    ExportLabels { path: String },
+    /// Replace the labels of the tracks in a .csv or .json file with the ones it contains
+    ImportLabels { path: String },
+}
+
+#[derive(Subcommand)]
+enum SublistAction {
+    /// Print the sublist tree with the number of tracks in every sublist
+    List,
+    /// Rename a sublist and its Spotify playlist
+    Rename {
+        from: String,
+        to: String,
+        /// Training run whose class names are renamed too, so it can still be used
+        #[arg(long)]
+        artifact_dir: Option<String>,
+    },
+    /// Nest a sublist under another one, or move it back to the top level
+    Nest {
+        name: String,
+        /// Leave out to make it a top level sublist
+        parent: Option<String>,
+    },
+    /// Delete a sublist, tracks left without a sublist become unlabelled
+    Delete { name: String },
+    /// Move every track of a sublist into another one and delete it
+    Merge { from: String, into: String },
+    /// Replace a sublist with new ones and sort its tracks into them
+    Split {
+        name: String,
+        #[arg(required = true, num_args = 2..)]
+        into: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RuleAction {
+    /// Print every rule with the number of tracks it matches
+    List,
+    /// Print the tracks a rule matches, without saving it
+    Test { rule: String },
+    /// Add or replace a smart sublist, one defined by a rule instead of labels
+    Add { name: String, rule: String },
+    /// Only let the model put tracks matching the rule in a learned sublist
+    Constrain { sublist: String, rule: String },
+    /// Remove a smart sublist or the constraint of a learned sublist
+    Remove { name: String },
+}
+
 // #[derive(Debug)]
 // enum CustomError {
 //     ClientError(ClientError),
@@ -18,12 +225,452 @@ use anyhow::{Context, Result};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Label {
+        seed_from_playlists: false,
+        artifact_dir: None,
+        order: None,
+        tui: false,
+    });
+    match cli.backend {
+        #[cfg(feature = "backend-ndarray")]
+        BackendKind::Ndarray => {
+            use burn::backend::{ndarray::NdArrayDevice, NdArray};
+            run::<Autodiff<NdArray>>(command, NdArrayDevice::Cpu).await
+        }
+        #[cfg(feature = "backend-wgpu")]
+        BackendKind::Wgpu => {
+            use burn::backend::{wgpu::WgpuDevice, Wgpu};
+            run::<Autodiff<Wgpu>>(command, WgpuDevice::BestAvailable).await
+        }
+        #[cfg(feature = "backend-cuda")]
+        BackendKind::Cuda => {
+            use burn::backend::{libtorch::LibTorchDevice, LibTorch};
+            run::<Autodiff<LibTorch>>(command, LibTorchDevice::Cuda(0)).await
+        }
+        #[cfg(feature = "backend-tch")]
+        BackendKind::Tch => {
+            use burn::backend::{libtorch::LibTorchDevice, LibTorch};
+            run::<Autodiff<LibTorch>>(command, LibTorchDevice::Cpu).await
+        }
+    }
+}
+
+async fn run<B: AutodiffBackend>(command: Command, device: B::Device) -> Result<()> {
+    match command {
+        Command::Label {
+            seed_from_playlists,
+            artifact_dir,
+            order,
+            tui,
+        } => {
+            let guide =
+                artifact_dir.map(|x| (artifacts::ArtifactDir::new(x), order.unwrap_or_default()));
+            label_pipeline::<B::InnerBackend>(seed_from_playlists, guide, tui, device).await
+        }
+        Command::Train {
+            artifact_dir,
+            config,
+            model,
+            normalization,
+            ablation,
+            resume,
+        } => {
+            let artifact_dir = artifacts::ArtifactDir::new(artifact_dir);
+            let mut config = match (resume, config) {
+                (true, _) => artifact_dir.load_config()?,
+                (false, Some(path)) => training::TrainingConfig::load(path)
+                    .context("Error in loading training config")?,
+                (false, None) => training::TrainingConfig::default(),
+            };
+            if let Some(model) = model {
+                config.model = model;
+            }
+            if let Some(normalization) = normalization {
+                config.normalization = normalization;
+            }
+            if ablation {
+                training::train_ablation::<B>(&artifact_dir, config, device)
+                    .context("Error in the ablation pipeline")?;
+            } else {
+                training::train::<B>(&artifact_dir, config, device, resume)
+                    .context("Error in the training pipeline")?;
+            }
+            Ok(())
+        }
+        Command::Predict {
+            artifact_dir,
+            format,
+            threshold,
+        } => {
+            let artifact_dir = artifacts::ArtifactDir::new(artifact_dir);
+            let thresholds = match threshold {
+                Some(global) => thresholds::Thresholds {
+                    global,
+                    ..Default::default()
+                },
+                None => thresholds::Thresholds::load(&artifact_dir)?,
+            };
+            let predictions = inference::predict_unlabelled::<B::InnerBackend>(
+                &artifact_dir,
+                &thresholds,
+                device,
+            )
+            .context("Error in the prediction pipeline")?;
+            inference::print_predictions(&predictions, format)
+        }
+        Command::TuneThresholds {
+            artifact_dir,
+            target_precision,
+        } => {
+            let artifact_dir = artifacts::ArtifactDir::new(artifact_dir);
+            let thresholds =
+                thresholds::tune::<B::InnerBackend>(&artifact_dir, device, target_precision)
+                    .context("Error in tuning thresholds")?;
+            thresholds
+                .per_sublist
+                .iter()
+                .for_each(|(sublist, threshold)| println!("{sublist:<30} {threshold:.3}"));
+            thresholds.save(&artifact_dir)
+        }
+        Command::Apply {
+            public,
+            include_unsorted,
+            include_descendants,
+            dry_run,
+            max_changes,
+            format,
+        } => {
+            let spotify = account::get_user_acct()
+                .await
+                .context("Error in account creation")?;
+            let sublists = dataset::read_sublists()?;
+            let mut store = label_store::LabelStore::load()?;
+            // Hand edits become manual labels first, so the plan never undoes them
+            let rules = rules::RuleConfig::load()?;
+            let mut applied = apply::AppliedState::load()?;
+            let mut edits = apply::detect_manual_edits(&spotify, &applied)
+                .await
+                .context("Error in detecting manual playlist edits")?;
+            // Smart playlists always follow their rule, there is no label to keep an edit in
+            edits.retain(|x| !rules.smart.contains_key(&x.sublist));
+            if format == misc_helpers::OutputFormat::Table {
+                edits.iter().for_each(|x| {
+                    println!(
+                        "Manually {} {}: {}",
+                        if x.added { "added to" } else { "removed from" },
+                        x.sublist,
+                        x.track_id
+                    )
+                });
+            }
+            apply::record_manual_edits(&mut store, &edits);
+            let options = apply::ApplyOptions {
+                public,
+                include_unsorted,
+                include_descendants,
+            };
+            let diffs = apply::plan(
+                &spotify,
+                &applied,
+                &sublists,
+                &hierarchy::Hierarchy::load()?,
+                &store,
+                &rules.assignments(&dataset::all_tracks()?),
+                options,
+            )
+            .await
+            .context("Error in comparing sublists to playlists")?;
+            apply::print_diff(&diffs, &dataset::track_names()?, format)?;
+
+            apply::check_change_limit(&diffs, max_changes)?;
+            if !dry_run {
+                if !edits.is_empty() {
+                    store.save().context("Error in saving labels")?;
+                    dataset::sync_manual_labels(&store)
+                        .context("Error in adding manual edits to the training set")?;
+                }
+                // Saved even if a request failed, so what did change isn't taken for hand edits
+                let result = apply::execute(&spotify, &mut applied, &diffs, options).await;
+                applied.save()?;
+                result.context("Error in applying sublists to playlists")?;
+            }
+            Ok(())
+        }
+        Command::Review => {
+            let mut store = label_store::LabelStore::load()?;
+            let items = tui::pending_predictions(&store, &dataset::all_tracks()?);
+            if items.is_empty() {
+                println!("No predictions waiting for review, run predict first");
+                return Ok(());
+            }
+            let decisions = tui::review(&items, &dataset::read_sublists()?)
+                .context("Error in the review session")?;
+            decisions
+                .into_iter()
+                .for_each(|(track_id, sublists)| store.set_manual(&track_id, sublists));
+            store.save().context("Error in saving labels")?;
+            dataset::sync_manual_labels(&store)
+                .context("Error in adding reviewed labels to the training set")
+        }
+        Command::Sublists { action } => sublist_pipeline(action).await,
+        Command::Rules { action } => rule_pipeline(action),
+        Command::Discover {
+            method,
+            min_clusters,
+            max_clusters,
+            representatives,
+            seed,
+        } => {
+            let tracks = dataset::all_tracks()?;
+            if tracks.is_empty() {
+                anyhow::bail!("There are no tracks to cluster, run label first");
+            }
+            let features: Vec<Vec<f32>> = tracks.iter().map(|x| x.feature_vector()).collect();
+            // Z-scores put every feature on the same scale and make cluster means readable
+            let normalizer =
+                normalizer::Normalizer::fit(normalizer::NormalizationKind::ZScore, &features)?;
+            let points: Vec<Vec<f32>> = features.iter().map(|x| normalizer.transform(x)).collect();
+            let discovery = clustering::discover(&points, method, min_clusters, max_clusters, seed);
+            discovery
+                .scores
+                .iter()
+                .for_each(|(k, score)| println!("{k:>3} clusters: {score:.3}"));
+
+            let feature_names = data_structs::TrimmedTrack::feature_names();
+            let mut sublists = sublists::Sublists::load()?;
+            for (i, cluster) in discovery.clusters.iter().enumerate() {
+                println!("\nCluster {} ({} tracks)", i + 1, cluster.members.len());
+                cluster
+                    .distinguishing_features(5)
+                    .iter()
+                    .for_each(|(f, z)| println!("  {:<28} {z:+.2} sd", feature_names[*f]));
+                let seeds: Vec<String> = cluster
+                    .members
+                    .iter()
+                    .take(representatives)
+                    .map(|x| {
+                        println!("  - {}", tracks[*x].text_description());
+                        tracks[*x].track_id.clone()
+                    })
+                    .collect();
+
+                println!("Name this cluster to make it a sublist, or press enter to skip it:");
+                let mut input = String::new();
+                std::io::stdin()
+                    .read_line(&mut input)
+                    .context("Error in reading cluster name")?;
+                let name = input.trim().to_string();
+                if name.is_empty() {
+                    continue;
+                }
+                if let Err(error) = sublists.add(&name, &[]) {
+                    println!("{error}");
+                    continue;
+                }
+                // The cluster only suggests tracks, each one is confirmed before it is labelled
+                println!("Label the tracks above that belong in {name:?}");
+                let labelled = sublists
+                    .relabel(&seeds, &[name], &tracks)
+                    .await
+                    .context("Error in the labelling session")?;
+                println!("{labelled} of {} tracks labelled", seeds.len());
+            }
+            sublists.save()
+        }
+        Command::Outliers {
+            method,
+            artifact_dir,
+            top,
+            format,
+        } => {
+            let artifact_dir = artifact_dir.map(artifacts::ArtifactDir::new);
+            let outliers = outliers::detect::<B::InnerBackend>(
+                method,
+                &label_store::LabelStore::load()?,
+                &dataset::all_tracks()?,
+                artifact_dir.as_ref(),
+                device,
+                top,
+            )
+            .context("Error in detecting outliers")?;
+            outliers::print_outliers(&outliers, format)
+        }
+        Command::ExportLabels { path } => {
+            let store = label_store::LabelStore::load()?;
+            let count = label_io::export(&store, &dataset::all_tracks()?, Path::new(&path))
+                .context("Error in exporting labels")?;
+            println!("Exported {count} labels to {path}");
+            Ok(())
+        }
+        Command::ImportLabels { path } => {
+            let mut store = label_store::LabelStore::load()?;
+            let report = label_io::import(
+                &mut store,
+                &dataset::all_tracks()?,
+                &dataset::read_sublists()?,
+                Path::new(&path),
+            )
+            .context("Error in importing labels")?;
+            report
+                .unknown_tracks
+                .iter()
+                .for_each(|x| println!("Unknown track, not in the motherlist: {x}"));
+            report
+                .unknown_sublists
+                .iter()
+                .for_each(|x| println!("Unknown sublist: {x:?}"));
+            report
+                .unchanged
+                .iter()
+                .for_each(|x| println!("No row left for {x}, its labels were kept"));
+            store.save().context("Error in saving labels")?;
+            dataset::sync_manual_labels(&store)
+                .context("Error in adding imported labels to the training set")?;
+            println!("Imported labels for {} tracks", report.imported);
+            Ok(())
+        }
+    }
+}
+
+async fn sublist_pipeline(action: SublistAction) -> Result<()> {
+    let mut sublists = sublists::Sublists::load()?;
+    let retrain = "Models trained before this change can't be used any more, train a new one";
+    match action {
+        SublistAction::List => {
+            let assignments = sublists.store.assignments();
+            sublists
+                .hierarchy
+                .tree(&sublists.names)
+                .into_iter()
+                .for_each(|(depth, name)| {
+                    let count = assignments.get(name).map_or(0, |x| x.len());
+                    println!("{count:>6} {}{name}", "  ".repeat(depth))
+                });
+            return Ok(());
+        }
+        SublistAction::Rename {
+            from,
+            to,
+            artifact_dir,
+        } => {
+            // Only sign in when there is a playlist to rename
+            let spotify = match sublists.applied.playlists.contains_key(&from) {
+                true => Some(
+                    account::get_user_acct()
+                        .await
+                        .context("Error in account creation")?,
+                ),
+                false => None,
+            };
+            sublists
+                .rename(spotify.as_ref(), &from, &to)
+                .await
+                .context("Error in renaming sublist")?;
+            if let Some(artifact_dir) = artifact_dir {
+                sublists::rename_in_model(&artifacts::ArtifactDir::new(artifact_dir), &from, &to)
+                    .context("Error in renaming the model's class")?;
+            }
+            println!("Renamed {from:?} to {to:?}");
+        }
+        SublistAction::Nest { name, parent } => {
+            sublists.nest(&name, parent.as_deref())?;
+            match parent {
+                Some(parent) => println!("Nested {name:?} under {parent:?}"),
+                None => println!("Moved {name:?} to the top level"),
+            }
+        }
+        SublistAction::Delete { name } => {
+            let count = sublists.delete(&name)?;
+            println!("Deleted {name:?}, {count} tracks lost the label. {retrain}");
+        }
+        SublistAction::Merge { from, into } => {
+            let count = sublists.merge(&from, &into)?;
+            println!("Merged {count} tracks of {from:?} into {into:?}. {retrain}");
+        }
+        SublistAction::Split { name, into } => {
+            let track_ids = sublists.split(&name, &into)?;
+            println!(
+                "Sort the {} tracks of {name:?} into {into:?}",
+                track_ids.len()
+            );
+            let labelled = sublists
+                .relabel(&track_ids, &into, &dataset::all_tracks()?)
+                .await
+                .context("Error in the relabelling session")?;
+            println!(
+                "{labelled} of {} tracks sorted, the rest are in none of the new sublists. \
+                 {retrain}",
+                track_ids.len()
+            );
+        }
+    }
+    sublists.save()
+}
+
+fn rule_pipeline(action: RuleAction) -> Result<()> {
+    let mut config = rules::RuleConfig::load()?;
+    let sublists = dataset::read_sublists()?;
+    match action {
+        RuleAction::List => {
+            let tracks = dataset::all_tracks()?;
+            let count = |rule: &rules::Rule| tracks.iter().filter(|x| rule.matches(x)).count();
+            config
+                .smart
+                .iter()
+                .for_each(|(name, rule)| println!("{:>6} {name}: {rule}", count(rule)));
+            config
+                .constraints
+                .iter()
+                .for_each(|(name, rule)| println!("{:>6} {name} requires: {rule}", count(rule)));
+            return Ok(());
+        }
+        RuleAction::Test { rule } => {
+            let rule = rules::Rule::try_from(rule)?;
+            let tracks = dataset::all_tracks()?;
+            let matched: Vec<_> = tracks.iter().filter(|x| rule.matches(x)).collect();
+            matched
+                .iter()
+                .for_each(|x| println!("{}", x.text_description()));
+            println!("{} of {} tracks match", matched.len(), tracks.len());
+            return Ok(());
+        }
+        RuleAction::Add { name, rule } => {
+            if sublists.contains(&name) || name == thresholds::UNSORTED {
+                anyhow::bail!("{name:?} is already a learned sublist");
+            }
+            config.smart.insert(name, rules::Rule::try_from(rule)?);
+        }
+        RuleAction::Constrain { sublist, rule } => {
+            if !sublists.contains(&sublist) {
+                anyhow::bail!("There is no learned sublist named {sublist:?}");
+            }
+            config
+                .constraints
+                .insert(sublist, rules::Rule::try_from(rule)?);
+        }
+        RuleAction::Remove { name } => {
+            if config.smart.remove(&name).is_none() && config.constraints.remove(&name).is_none() {
+                anyhow::bail!("There is no rule for {name:?}");
+            }
+        }
+    }
+    config.save()
+}
+
+async fn label_pipeline<B: Backend>(
+    seed_from_playlists: bool,
+    guide: Option<(artifacts::ArtifactDir, active_learning::SamplingStrategy)>,
+    tui: bool,
+    device: B::Device,
+) -> Result<()> {
     // All actions relating to account pre-analysis
-    let (motherlist, labels) = account_details()
-        .await
-        .context("Error in the account details pre-analysis pipeline")?;
+    let (motherlist, sublists, labels) =
+        account_details::<B>(seed_from_playlists, guide, tui, device)
+            .await
+            .context("Error in the account details pre-analysis pipeline")?;
     //Create database
-    database_pipeline(&motherlist, &labels)
+    database_pipeline(&motherlist, &sublists, &labels)
         .await
         .context("Error in the database pipeline")?;
 
@@ -35,7 +682,12 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn account_details() -> Result<(Vec<data_structs::TrimmedTrack>, Vec<Option<u32>>)> {
+async fn account_details<B: Backend>(
+    seed_from_playlists: bool,
+    guide: Option<(artifacts::ArtifactDir, active_learning::SamplingStrategy)>,
+    tui: bool,
+    device: B::Device,
+) -> Result<(Vec<data_structs::TrimmedTrack>, Vec<String>, Vec<Vec<u32>>)> {
     // Get user account
     let spotify = account::get_user_acct()
         .await
@@ -44,11 +696,8 @@ async fn account_details() -> Result<(Vec<data_structs::TrimmedTrack>, Vec<Optio
     let motherlist = account::get_motherlist(&spotify)
         .await
         .context("Error in getting motherlist")?;
-    // Create sublists as a list of names for the sublists
-    let sublists = labels::get_sublists().await;
-    // Get labels for a subset of the motherlist - This becomes our training set
-    let labels = labels::get_labels(sublists.as_slice(), &motherlist).await;
-    // Restructure motherlist into a list of TrimmedTrack
+    // Restructure motherlist into a list of TrimmedTrack, a model needs the features to guide
+    // labelling
     let motherlist: Vec<data_structs::TrimmedTrack> = join_all(
         motherlist
             .into_iter()
@@ -58,15 +707,66 @@ async fn account_details() -> Result<(Vec<data_structs::TrimmedTrack>, Vec<Optio
     .into_iter()
     .collect::<Result<Vec<_>>>()
     .context("Error in trimming motherlist")?;
-    Ok((motherlist, labels))
+    let store = label_store::LabelStore::load()?;
+
+    let (sublists, labels) = match (seed_from_playlists, guide) {
+        // Sublists and labels both come from playlists the user already curated
+        (true, _) => labels::seed_from_playlists(&spotify, &motherlist, &store)
+            .await
+            .context("Error in seeding labels from playlists")?,
+        // Keep labelling the sublists the model was trained on
+        (false, Some((artifact_dir, strategy))) => {
+            let sublists = artifact_dir.load_class_names()?;
+            let labels = labels::stored_labels(&store, &sublists, &motherlist);
+            let guidance = active_learning::guidance::<B>(
+                &artifact_dir,
+                device,
+                &motherlist,
+                &labels,
+                strategy,
+            )
+            .context("Error in ordering tracks with the model")?;
+            let labels = match tui {
+                true => tui::label_tracks(&sublists, &motherlist, labels, Some(guidance)),
+                false => {
+                    labels::get_labels(&sublists, &motherlist, labels, Some(guidance), true).await
+                }
+            }
+            .context("Error in the labelling session")?;
+            (sublists, labels)
+        }
+        (false, None) => {
+            // Create sublists as a list of names for the sublists
+            let (sublists, hierarchy) = labels::get_sublists().await;
+            hierarchy.save()?;
+            // Get labels for a subset of the motherlist - This becomes our training set
+            let labels = labels::stored_labels(&store, &sublists, &motherlist);
+            let labels = match tui {
+                true => tui::label_tracks(&sublists, &motherlist, labels, None),
+                false => labels::get_labels(&sublists, &motherlist, labels, None, true).await,
+            }
+            .context("Error in the labelling session")?;
+            (sublists, labels)
+        }
+    };
+    Ok((motherlist, sublists, labels))
 }
 
 async fn database_pipeline(
     motherlist: &[data_structs::TrimmedTrack],
-    labels: &[Option<u32>],
+    sublists: &[String],
+    labels: &[Vec<u32>],
 ) -> Result<()> {
     dataset::write_to_db(motherlist, labels)
         .await
         .context("Error in creating/writing to database pipeline")?;
+    dataset::write_sublists(sublists).context("Error in saving sublists")?;
+    // Sublists seeded from playlists may have replaced the nested ones
+    let mut hierarchy = hierarchy::Hierarchy::load()?;
+    hierarchy.retain(sublists);
+    hierarchy.save()?;
+    let mut store = label_store::LabelStore::load()?;
+    store.record_manual(motherlist, sublists, labels);
+    store.save().context("Error in saving labels")?;
     Ok(())
 }
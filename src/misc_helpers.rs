@@ -22,3 +22,21 @@ pub fn convert_mode_to_int(mode: rspotify::model::enums::misc::Modality) -> i32
         rspotify::model::Modality::NoResult => -1,
     }
 }
+
+/// How commands that report results print them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable table
+    #[default]
+    Table,
+    /// JSON for further processing
+    Json,
+}
+
+/// Shortens a string to fit in a table column.
+pub fn truncate(value: &str, width: usize) -> String {
+    match value.chars().count() > width {
+        true => format!("{}…", value.chars().take(width - 1).collect::<String>()),
+        false => value.to_string(),
+    }
+}
@@ -0,0 +1,663 @@
+use anyhow::{bail, Result};
+use burn::{
+    config::Config,
+    constant,
+    module::Module,
+    nn::{
+        loss::CrossEntropyLossConfig,
+        transformer::{TransformerEncoder, TransformerEncoderConfig, TransformerEncoderInput},
+        Dropout, DropoutConfig, Embedding, EmbeddingConfig, Linear, LinearConfig,
+    },
+    tensor::{
+        activation::{gelu, relu},
+        backend::{AutodiffBackend, Backend},
+        Bool, Int, Tensor,
+    },
+    train::{ClassificationOutput, TrainOutput, TrainStep, ValidStep},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::batcher::{
+    FusionTrainingBatch, SegmentSequenceTrainingBatch, TextClassificationTrainingBatch,
+};
+use crate::data_structs::{FEATURE_COUNT, SEGMENT_FEATURE_COUNT};
+
+/// Non-linearity applied after every hidden layer of the [`MlpClassifier`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Activation {
+    Relu,
+    Gelu,
+    Tanh,
+}
+
+impl Activation {
+    pub fn forward<B: Backend, const D: usize>(&self, x: Tensor<B, D>) -> Tensor<B, D> {
+        match self {
+            Activation::Relu => relu(x),
+            Activation::Gelu => gelu(x),
+            Activation::Tanh => x.tanh(),
+        }
+    }
+}
+
+// The activation holds no parameters, so the model can keep it as a constant
+constant!(Activation);
+
+/// Cross entropy over the classes, or an independent binary cross entropy per class when a track
+/// can be in several sublists. The accuracy metric compares the top class against the first
+/// label, so it is only used for single-label models.
+fn classification_output<B: Backend>(
+    output: Tensor<B, 2>,
+    targets: Tensor<B, 1, Int>,
+    label_sets: Tensor<B, 2>,
+    multi_label: bool,
+) -> ClassificationOutput<B> {
+    let loss = match multi_label {
+        true => binary_cross_entropy_with_logits(output.clone(), label_sets),
+        false => CrossEntropyLossConfig::new()
+            .init()
+            .forward(output.clone(), targets.clone()),
+    };
+
+    ClassificationOutput {
+        loss,
+        output,
+        targets,
+    }
+}
+
+// max(x, 0) - x * y + log(1 + exp(-|x|)), which doesn't overflow for large logits
+fn binary_cross_entropy_with_logits<B: Backend>(
+    logits: Tensor<B, 2>,
+    targets: Tensor<B, 2>,
+) -> Tensor<B, 1> {
+    let loss = logits.clone().clamp_min(0.0) - logits.clone() * targets
+        + logits.abs().neg().exp().add_scalar(1.0).log();
+    loss.mean()
+}
+
+#[derive(Config)]
+pub struct MlpClassifierConfig {
+    /// Width of each hidden layer, in order from input to output.
+    #[config(default = "vec![64, 32]")]
+    pub hidden_sizes: Vec<usize>,
+    #[config(default = 0.2)]
+    pub dropout: f64,
+    #[config(default = "Activation::Relu")]
+    pub activation: Activation,
+}
+
+/// Multilayer perceptron over the fixed-length feature vector of a track.
+#[derive(Module, Debug)]
+pub struct MlpClassifier<B: Backend> {
+    hidden: Vec<Linear<B>>,
+    output: Linear<B>,
+    dropout: Dropout,
+    activation: Activation,
+    multi_label: bool,
+}
+
+impl MlpClassifierConfig {
+    /// Creates the model, the number of classes and whether a track can be in several of them come
+    /// from the sublists rather than the config.
+    pub fn init<B: Backend>(&self, n_classes: usize, multi_label: bool) -> MlpClassifier<B> {
+        let mut hidden = Vec::with_capacity(self.hidden_sizes.len());
+        let mut d_input = FEATURE_COUNT;
+        for d_hidden in self.hidden_sizes.iter() {
+            hidden.push(LinearConfig::new(d_input, *d_hidden).init());
+            d_input = *d_hidden;
+        }
+
+        MlpClassifier {
+            hidden,
+            output: LinearConfig::new(d_input, n_classes).init(),
+            dropout: DropoutConfig::new(self.dropout).init(),
+            activation: self.activation,
+            multi_label,
+        }
+    }
+}
+
+impl<B: Backend> MlpClassifier<B> {
+    /// Returns the output of the last hidden layer for a [batch, FEATURE_COUNT] tensor.
+    pub fn encode(&self, features: Tensor<B, 2>) -> Tensor<B, 2> {
+        let mut x = features;
+        for layer in self.hidden.iter() {
+            x = layer.forward(x);
+            x = self.activation.forward(x);
+            x = self.dropout.forward(x);
+        }
+        x
+    }
+
+    /// Returns the unnormalized class scores for a [batch, FEATURE_COUNT] tensor.
+    pub fn forward(&self, features: Tensor<B, 2>) -> Tensor<B, 2> {
+        self.output.forward(self.encode(features))
+    }
+
+    pub fn forward_classification(
+        &self,
+        item: TextClassificationTrainingBatch<B>,
+    ) -> ClassificationOutput<B> {
+        let (targets, label_sets) = (item.labels, item.label_sets);
+        let output = self.forward(item.features);
+        classification_output(output, targets, label_sets, self.multi_label)
+    }
+}
+
+impl<B: AutodiffBackend> TrainStep<TextClassificationTrainingBatch<B>, ClassificationOutput<B>>
+    for MlpClassifier<B>
+{
+    fn step(
+        &self,
+        item: TextClassificationTrainingBatch<B>,
+    ) -> TrainOutput<ClassificationOutput<B>> {
+        let item = self.forward_classification(item);
+        let grads = item.loss.backward();
+
+        TrainOutput::new(self, grads, item)
+    }
+}
+
+impl<B: Backend> ValidStep<TextClassificationTrainingBatch<B>, ClassificationOutput<B>>
+    for MlpClassifier<B>
+{
+    fn step(&self, item: TextClassificationTrainingBatch<B>) -> ClassificationOutput<B> {
+        self.forward_classification(item)
+    }
+}
+
+#[derive(Config)]
+pub struct SegmentTransformerConfig {
+    #[config(default = "TransformerEncoderConfig::new(64, 128, 4, 2)")]
+    pub transformer: TransformerEncoderConfig,
+    /// Longest segment sequence the positional embedding covers, longer tracks are cropped.
+    #[config(default = 512)]
+    pub max_segments: usize,
+    /// Keep every `stride`th segment to cover more of each track within `max_segments`.
+    #[config(default = 1)]
+    pub stride: usize,
+}
+
+/// Transformer encoder over the segment pitch/timbre sequence of a track, mean pooled over the
+/// unpadded segments into a classification head.
+#[derive(Module, Debug)]
+pub struct SegmentTransformer<B: Backend> {
+    input: Linear<B>,
+    embedding_pos: Embedding<B>,
+    transformer: TransformerEncoder<B>,
+    output: Linear<B>,
+    multi_label: bool,
+}
+
+impl SegmentTransformerConfig {
+    pub fn init<B: Backend>(&self, n_classes: usize, multi_label: bool) -> SegmentTransformer<B> {
+        let d_model = self.transformer.d_model;
+
+        SegmentTransformer {
+            input: LinearConfig::new(SEGMENT_FEATURE_COUNT, d_model).init(),
+            embedding_pos: EmbeddingConfig::new(self.max_segments, d_model).init(),
+            transformer: self.transformer.init(),
+            output: LinearConfig::new(d_model, n_classes).init(),
+            multi_label,
+        }
+    }
+}
+
+impl<B: Backend> SegmentTransformer<B> {
+    /// Encodes a [batch, segments, SEGMENT_FEATURE_COUNT] tensor into a [batch, d_model] tensor.
+    pub fn encode(&self, segments: Tensor<B, 3>, mask_pad: Tensor<B, 2, Bool>) -> Tensor<B, 2> {
+        let [batch_size, seq_length, _] = segments.dims();
+        let device = segments.device();
+
+        let positions = Tensor::<B, 1, Int>::arange(0..seq_length)
+            .reshape([1, seq_length])
+            .repeat(0, batch_size)
+            .to_device(&device);
+        let embedding = self.input.forward(segments) + self.embedding_pos.forward(positions);
+        let encoded = self
+            .transformer
+            .forward(TransformerEncoderInput::new(embedding).mask_pad(mask_pad.clone()));
+        let [_, _, d_model] = encoded.dims();
+
+        // Mean over the segments that aren't padding
+        let keep = mask_pad
+            .float()
+            .neg()
+            .add_scalar(1.0)
+            .reshape([batch_size, seq_length, 1]);
+        let summed = (encoded * keep.clone()).sum_dim(1);
+        let counts = keep.sum_dim(1).clamp_min(1.0);
+        (summed / counts).reshape([batch_size, d_model])
+    }
+
+    pub fn forward(&self, segments: Tensor<B, 3>, mask_pad: Tensor<B, 2, Bool>) -> Tensor<B, 2> {
+        self.output.forward(self.encode(segments, mask_pad))
+    }
+
+    pub fn forward_classification(
+        &self,
+        item: SegmentSequenceTrainingBatch<B>,
+    ) -> ClassificationOutput<B> {
+        let (targets, label_sets) = (item.labels, item.label_sets);
+        let output = self.forward(item.segments, item.mask_pad);
+        classification_output(output, targets, label_sets, self.multi_label)
+    }
+}
+
+impl<B: AutodiffBackend> TrainStep<SegmentSequenceTrainingBatch<B>, ClassificationOutput<B>>
+    for SegmentTransformer<B>
+{
+    fn step(&self, item: SegmentSequenceTrainingBatch<B>) -> TrainOutput<ClassificationOutput<B>> {
+        let item = self.forward_classification(item);
+        let grads = item.loss.backward();
+
+        TrainOutput::new(self, grads, item)
+    }
+}
+
+impl<B: Backend> ValidStep<SegmentSequenceTrainingBatch<B>, ClassificationOutput<B>>
+    for SegmentTransformer<B>
+{
+    fn step(&self, item: SegmentSequenceTrainingBatch<B>) -> ClassificationOutput<B> {
+        self.forward_classification(item)
+    }
+}
+
+#[derive(Config)]
+pub struct TextTransformerConfig {
+    #[config(default = "TransformerEncoderConfig::new(128, 256, 4, 2)")]
+    pub transformer: TransformerEncoderConfig,
+    /// Concatenate the normalized audio features to the text encoding before the head.
+    #[config(default = false)]
+    pub with_features: bool,
+}
+
+/// Transformer encoder over the tokenized "track — artists — album" text of a track.
+#[derive(Module, Debug)]
+pub struct TextTransformer<B: Backend> {
+    embedding_token: Embedding<B>,
+    embedding_pos: Embedding<B>,
+    transformer: TransformerEncoder<B>,
+    output: Linear<B>,
+    with_features: bool,
+    multi_label: bool,
+}
+
+impl TextTransformerConfig {
+    pub fn init<B: Backend>(
+        &self,
+        n_classes: usize,
+        vocab_size: usize,
+        max_seq_length: usize,
+        multi_label: bool,
+    ) -> TextTransformer<B> {
+        let d_model = self.transformer.d_model;
+        let d_head = match self.with_features {
+            true => d_model + FEATURE_COUNT,
+            false => d_model,
+        };
+
+        TextTransformer {
+            embedding_token: EmbeddingConfig::new(vocab_size, d_model).init(),
+            embedding_pos: EmbeddingConfig::new(max_seq_length, d_model).init(),
+            transformer: self.transformer.init(),
+            output: LinearConfig::new(d_head, n_classes).init(),
+            with_features: self.with_features,
+            multi_label,
+        }
+    }
+}
+
+impl<B: Backend> TextTransformer<B> {
+    /// Encodes a [batch, tokens] tensor into a [batch, d_model] tensor, using the encoding of the
+    /// leading [CLS] token.
+    pub fn encode(&self, tokens: Tensor<B, 2, Int>, mask_pad: Tensor<B, 2, Bool>) -> Tensor<B, 2> {
+        let [batch_size, seq_length] = tokens.dims();
+        let device = tokens.device();
+
+        let positions = Tensor::<B, 1, Int>::arange(0..seq_length)
+            .reshape([1, seq_length])
+            .repeat(0, batch_size)
+            .to_device(&device);
+        let embedding =
+            (self.embedding_token.forward(tokens) + self.embedding_pos.forward(positions)) / 2;
+        let encoded = self
+            .transformer
+            .forward(TransformerEncoderInput::new(embedding).mask_pad(mask_pad));
+        let [_, _, d_model] = encoded.dims();
+
+        encoded
+            .slice([0..batch_size, 0..1])
+            .reshape([batch_size, d_model])
+    }
+
+    pub fn forward(
+        &self,
+        tokens: Tensor<B, 2, Int>,
+        mask_pad: Tensor<B, 2, Bool>,
+        features: Tensor<B, 2>,
+    ) -> Tensor<B, 2> {
+        let encoded = self.encode(tokens, mask_pad);
+        let encoded = match self.with_features {
+            true => Tensor::cat(vec![encoded, features], 1),
+            false => encoded,
+        };
+        self.output.forward(encoded)
+    }
+
+    pub fn forward_classification(
+        &self,
+        item: TextClassificationTrainingBatch<B>,
+    ) -> ClassificationOutput<B> {
+        let (targets, label_sets) = (item.labels, item.label_sets);
+        let output = self.forward(item.tokens, item.mask_pad, item.features);
+        classification_output(output, targets, label_sets, self.multi_label)
+    }
+}
+
+impl<B: AutodiffBackend> TrainStep<TextClassificationTrainingBatch<B>, ClassificationOutput<B>>
+    for TextTransformer<B>
+{
+    fn step(
+        &self,
+        item: TextClassificationTrainingBatch<B>,
+    ) -> TrainOutput<ClassificationOutput<B>> {
+        let item = self.forward_classification(item);
+        let grads = item.loss.backward();
+
+        TrainOutput::new(self, grads, item)
+    }
+}
+
+impl<B: Backend> ValidStep<TextClassificationTrainingBatch<B>, ClassificationOutput<B>>
+    for TextTransformer<B>
+{
+    fn step(&self, item: TextClassificationTrainingBatch<B>) -> ClassificationOutput<B> {
+        self.forward_classification(item)
+    }
+}
+
+/// Which branches the [`FusionClassifier`] concatenates before its classification head.
+#[derive(Config)]
+pub struct FusionConfig {
+    #[config(default = true)]
+    pub use_features: bool,
+    #[config(default = true)]
+    pub use_sequence: bool,
+    #[config(default = true)]
+    pub use_text: bool,
+}
+
+impl FusionConfig {
+    /// Short name of the enabled branches, used to label ablation runs.
+    pub fn name(&self) -> String {
+        let branches: Vec<&str> = [
+            (self.use_features, "features"),
+            (self.use_sequence, "sequence"),
+            (self.use_text, "text"),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, name)| name)
+        .collect();
+        branches.join("+")
+    }
+
+    /// Every non-empty combination of the branches enabled in this config.
+    pub fn ablations(&self) -> Vec<FusionConfig> {
+        (1..8u8)
+            .filter(|bits| {
+                (bits & 1 == 0 || self.use_features)
+                    && (bits & 2 == 0 || self.use_sequence)
+                    && (bits & 4 == 0 || self.use_text)
+            })
+            .map(|bits| {
+                FusionConfig::new()
+                    .with_use_features(bits & 1 != 0)
+                    .with_use_sequence(bits & 2 != 0)
+                    .with_use_text(bits & 4 != 0)
+            })
+            .collect()
+    }
+
+    /// Creates the model from the configs of its branches, branches that are disabled are left
+    /// out. Fails if every branch is disabled.
+    pub fn init<B: Backend>(
+        &self,
+        n_classes: usize,
+        vocab_size: usize,
+        branches: FusionBranches,
+        multi_label: bool,
+    ) -> Result<FusionClassifier<B>> {
+        if !(self.use_features || self.use_sequence || self.use_text) {
+            bail!("The fusion model needs at least one of its branches enabled");
+        }
+        let mut d_output = 0;
+        if self.use_features {
+            d_output += branches
+                .mlp
+                .hidden_sizes
+                .last()
+                .copied()
+                .unwrap_or(FEATURE_COUNT);
+        }
+        if self.use_sequence {
+            d_output += branches.sequence.transformer.d_model;
+        }
+        if self.use_text {
+            d_output += branches.text.transformer.d_model;
+        }
+
+        // Only the encoders of the branches are used, so their own heads' loss never matters
+        Ok(FusionClassifier {
+            features: self
+                .use_features
+                .then(|| branches.mlp.init(n_classes, false)),
+            sequence: self
+                .use_sequence
+                .then(|| branches.sequence.init(n_classes, false)),
+            text: self.use_text.then(|| {
+                branches
+                    .text
+                    .init(n_classes, vocab_size, branches.max_seq_length, false)
+            }),
+            output: LinearConfig::new(d_output, n_classes).init(),
+            multi_label,
+        })
+    }
+}
+
+/// Configs of the models whose encoders the [`FusionClassifier`] uses as branches.
+#[derive(Debug, Clone, Copy)]
+pub struct FusionBranches<'a> {
+    pub mlp: &'a MlpClassifierConfig,
+    pub sequence: &'a SegmentTransformerConfig,
+    pub text: &'a TextTransformerConfig,
+    pub max_seq_length: usize,
+}
+
+/// Multimodal classifier concatenating the encodings of the feature MLP, the segment sequence
+/// transformer and the text transformer.
+#[derive(Module, Debug)]
+pub struct FusionClassifier<B: Backend> {
+    features: Option<MlpClassifier<B>>,
+    sequence: Option<SegmentTransformer<B>>,
+    text: Option<TextTransformer<B>>,
+    output: Linear<B>,
+    multi_label: bool,
+}
+
+impl<B: Backend> FusionClassifier<B> {
+    pub fn forward(
+        &self,
+        tokens: Tensor<B, 2, Int>,
+        mask_pad: Tensor<B, 2, Bool>,
+        features: Tensor<B, 2>,
+        segments: Tensor<B, 3>,
+        segments_mask_pad: Tensor<B, 2, Bool>,
+    ) -> Tensor<B, 2> {
+        let mut encoded = Vec::with_capacity(3);
+        if let Some(branch) = &self.features {
+            encoded.push(branch.encode(features));
+        }
+        if let Some(branch) = &self.sequence {
+            encoded.push(branch.encode(segments, segments_mask_pad));
+        }
+        if let Some(branch) = &self.text {
+            encoded.push(branch.encode(tokens, mask_pad));
+        }
+        self.output.forward(Tensor::cat(encoded, 1))
+    }
+
+    pub fn forward_classification(&self, item: FusionTrainingBatch<B>) -> ClassificationOutput<B> {
+        let (targets, label_sets) = (item.song.labels, item.song.label_sets);
+        let output = self.forward(
+            item.song.tokens,
+            item.song.mask_pad,
+            item.song.features,
+            item.segments,
+            item.segments_mask_pad,
+        );
+        classification_output(output, targets, label_sets, self.multi_label)
+    }
+}
+
+impl<B: AutodiffBackend> TrainStep<FusionTrainingBatch<B>, ClassificationOutput<B>>
+    for FusionClassifier<B>
+{
+    fn step(&self, item: FusionTrainingBatch<B>) -> TrainOutput<ClassificationOutput<B>> {
+        let item = self.forward_classification(item);
+        let grads = item.loss.backward();
+
+        TrainOutput::new(self, grads, item)
+    }
+}
+
+impl<B: Backend> ValidStep<FusionTrainingBatch<B>, ClassificationOutput<B>>
+    for FusionClassifier<B>
+{
+    fn step(&self, item: FusionTrainingBatch<B>) -> ClassificationOutput<B> {
+        self.forward_classification(item)
+    }
+}
+
+#[cfg(all(test, feature = "backend-ndarray"))]
+mod tests {
+    use super::*;
+    use burn::backend::NdArray;
+    use burn::tensor::{Data, ElementConversion};
+
+    type B = NdArray;
+
+    const BATCH: usize = 3;
+    const N_CLASSES: usize = 4;
+    const VOCAB: usize = 20;
+    const SEQ_LENGTH: usize = 6;
+
+    fn small_transformer() -> TransformerEncoderConfig {
+        TransformerEncoderConfig::new(8, 16, 2, 1)
+    }
+
+    fn tokens() -> (Tensor<B, 2, Int>, Tensor<B, 2, Bool>) {
+        let tokens = Tensor::<B, 2, Int>::ones([BATCH, SEQ_LENGTH]);
+        // Last position of every row is padding
+        let mask = Tensor::<B, 2, Int>::zeros([BATCH, SEQ_LENGTH])
+            .slice_assign(
+                [0..BATCH, SEQ_LENGTH - 1..SEQ_LENGTH],
+                Tensor::ones([BATCH, 1]),
+            )
+            .equal_elem(1);
+        (tokens, mask)
+    }
+
+    fn segments() -> (Tensor<B, 3>, Tensor<B, 2, Bool>) {
+        let segments = Tensor::<B, 3>::ones([BATCH, 5, SEGMENT_FEATURE_COUNT]);
+        let mask = Tensor::<B, 2, Int>::zeros([BATCH, 5]).equal_elem(1);
+        (segments, mask)
+    }
+
+    fn assert_finite(output: Tensor<B, 2>) {
+        assert_eq!(output.dims(), [BATCH, N_CLASSES]);
+        assert!(output.into_data().value.iter().all(|x| x.is_finite()));
+    }
+
+    #[test]
+    fn mlp_forward_gives_one_score_per_class() {
+        let model: MlpClassifier<B> = MlpClassifierConfig::new()
+            .with_hidden_sizes(vec![16, 8])
+            .init(N_CLASSES, false);
+        assert_finite(model.forward(Tensor::ones([BATCH, FEATURE_COUNT])));
+    }
+
+    #[test]
+    fn sequence_forward_gives_one_score_per_class() {
+        let model: SegmentTransformer<B> = SegmentTransformerConfig::new()
+            .with_transformer(small_transformer())
+            .init(N_CLASSES, false);
+        let (segments, mask) = segments();
+        assert_finite(model.forward(segments, mask));
+    }
+
+    #[test]
+    fn text_forward_gives_one_score_per_class() {
+        let model: TextTransformer<B> = TextTransformerConfig::new()
+            .with_transformer(small_transformer())
+            .with_with_features(true)
+            .init(N_CLASSES, VOCAB, SEQ_LENGTH, false);
+        let (tokens, mask) = tokens();
+        assert_finite(model.forward(tokens, mask, Tensor::ones([BATCH, FEATURE_COUNT])));
+    }
+
+    #[test]
+    fn fusion_forward_gives_one_score_per_class() {
+        let mlp = MlpClassifierConfig::new().with_hidden_sizes(vec![16]);
+        let sequence = SegmentTransformerConfig::new().with_transformer(small_transformer());
+        let text = TextTransformerConfig::new().with_transformer(small_transformer());
+        let branches = FusionBranches {
+            mlp: &mlp,
+            sequence: &sequence,
+            text: &text,
+            max_seq_length: SEQ_LENGTH,
+        };
+        for fusion in FusionConfig::new().ablations() {
+            let model: FusionClassifier<B> =
+                fusion.init(N_CLASSES, VOCAB, branches, false).unwrap();
+            let (tokens, mask) = tokens();
+            let (segments, segments_mask) = segments();
+            assert_finite(model.forward(
+                tokens,
+                mask,
+                Tensor::ones([BATCH, FEATURE_COUNT]),
+                segments,
+                segments_mask,
+            ));
+        }
+    }
+
+    #[test]
+    fn fusion_without_branches_is_rejected() {
+        let fusion = FusionConfig::new()
+            .with_use_features(false)
+            .with_use_sequence(false)
+            .with_use_text(false);
+        let branches = FusionBranches {
+            mlp: &MlpClassifierConfig::new(),
+            sequence: &SegmentTransformerConfig::new(),
+            text: &TextTransformerConfig::new(),
+            max_seq_length: SEQ_LENGTH,
+        };
+        assert!(fusion.init::<B>(N_CLASSES, VOCAB, branches, false).is_err());
+    }
+
+    #[test]
+    fn binary_cross_entropy_matches_the_closed_form() {
+        // Logits of 0 give a probability of 0.5, so the loss is ln 2 whatever the target
+        let logits = Tensor::<B, 2>::zeros([2, 2]);
+        let targets = Tensor::<B, 2>::from_data(Data::from([[1.0, 0.0], [0.0, 1.0]]).convert());
+        let loss = binary_cross_entropy_with_logits(logits, targets)
+            .into_scalar()
+            .elem::<f32>();
+        assert!((loss - std::f32::consts::LN_2).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,102 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::data_structs::FEATURE_COUNT;
+
+/// How each feature column gets rescaled before being fed to a model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+pub enum NormalizationKind {
+    /// Subtract the mean and divide by the standard deviation.
+    #[default]
+    ZScore,
+    /// Map the observed minimum to 0 and maximum to 1.
+    MinMax,
+    /// Subtract the median and divide by the interquartile range, so outliers don't dominate.
+    Robust,
+}
+
+/// Per-feature statistics fitted on the labelled training split.
+/// These are saved to disk so inference applies the exact same transform the model was trained on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Normalizer {
+    pub kind: NormalizationKind,
+    center: Vec<f32>,
+    scale: Vec<f32>,
+}
+
+impl Normalizer {
+    /// Fits the normalizer on a set of feature vectors, all of length [`FEATURE_COUNT`].
+    pub fn fit(kind: NormalizationKind, rows: &[Vec<f32>]) -> Result<Self> {
+        if rows.is_empty() {
+            bail!("Cannot fit a normalizer without any labelled tracks");
+        }
+
+        let mut center = Vec::with_capacity(FEATURE_COUNT);
+        let mut scale = Vec::with_capacity(FEATURE_COUNT);
+        for column in 0..FEATURE_COUNT {
+            let mut values: Vec<f32> = rows.iter().map(|row| row[column]).collect();
+            let (c, s) = match kind {
+                NormalizationKind::ZScore => {
+                    let mean = values.iter().sum::<f32>() / values.len() as f32;
+                    let variance = values.iter().map(|x| (x - mean).powi(2)).sum::<f32>()
+                        / values.len() as f32;
+                    (mean, variance.sqrt())
+                }
+                NormalizationKind::MinMax => {
+                    let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+                    let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                    (min, max - min)
+                }
+                NormalizationKind::Robust => {
+                    values.sort_by(|a, b| a.total_cmp(b));
+                    let median = quantile(&values, 0.5);
+                    (median, quantile(&values, 0.75) - quantile(&values, 0.25))
+                }
+            };
+            center.push(c);
+            // Constant columns would otherwise divide by zero, leave them centred but unscaled
+            scale.push(if s.abs() < f32::EPSILON { 1.0 } else { s });
+        }
+
+        Ok(Self {
+            kind,
+            center,
+            scale,
+        })
+    }
+
+    /// Applies the fitted transform to a single feature vector.
+    pub fn transform(&self, features: &[f32]) -> Vec<f32> {
+        features
+            .iter()
+            .zip(self.center.iter().zip(self.scale.iter()))
+            .map(|(x, (c, s))| (x - c) / s)
+            .collect()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path).context("Error in creating normalizer file")?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)
+            .context("Error in writing normalizer statistics")?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).context("Error in opening normalizer file")?;
+        serde_json::from_reader(BufReader::new(file))
+            .context("Error in reading normalizer statistics")
+    }
+}
+
+// Expects `sorted` to be non-empty and sorted in ascending order
+fn quantile(sorted: &[f32], q: f32) -> f32 {
+    let position = q * (sorted.len() - 1) as f32;
+    let lower = position.floor() as usize;
+    let upper = position.ceil() as usize;
+    let weight = position - lower as f32;
+    sorted[lower] * (1.0 - weight) + sorted[upper] * weight
+}
@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use burn::tensor::backend::Backend;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::Serialize;
+
+use crate::artifacts::ArtifactDir;
+use crate::data_structs::TrimmedTrack;
+use crate::inference::Predictor;
+use crate::label_store::{LabelSource, LabelStore};
+use crate::misc_helpers::{truncate, OutputFormat};
+use crate::normalizer::{NormalizationKind, Normalizer};
+use crate::thresholds::UNSORTED;
+
+/// How far from the rest of its sublist a track is judged to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutlierMethod {
+    /// Distance to the sublist's mean, scaled by how its features vary together
+    #[default]
+    Mahalanobis,
+    /// How quickly random splits of the sublist's features isolate the track
+    IsolationForest,
+    /// How unlikely a trained model finds it that the track is in the sublist
+    Confidence,
+}
+
+/// A track that probably doesn't belong in a sublist it is in.
+#[derive(Debug, Clone, Serialize)]
+pub struct Outlier {
+    pub sublist: String,
+    pub track_id: String,
+    pub track_name: String,
+    pub artists: Vec<String>,
+    /// Whether the track is in the sublist because of a manual label or a prediction.
+    pub source: LabelSource,
+    /// Higher is more unusual, only comparable within a method.
+    pub score: f32,
+}
+
+// Sublists this small have no distribution to speak of
+const MIN_MEMBERS: usize = 5;
+
+/// Scores every member of every sublist and returns the `top` most unusual tracks of each,
+/// most unusual first. `artifact_dir` is only needed for [`OutlierMethod::Confidence`].
+pub fn detect<B: Backend>(
+    method: OutlierMethod,
+    store: &LabelStore,
+    tracks: &[TrimmedTrack],
+    artifact_dir: Option<&ArtifactDir>,
+    device: B::Device,
+    top: usize,
+) -> Result<Vec<Outlier>> {
+    let index_of: HashMap<&str, usize> = tracks
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.track_id.as_str(), i))
+        .collect();
+    let features: Vec<Vec<f32>> = tracks.iter().map(|x| x.feature_vector()).collect();
+    let normalizer = Normalizer::fit(NormalizationKind::ZScore, &features)?;
+    let points: Vec<Vec<f32>> = features.iter().map(|x| normalizer.transform(x)).collect();
+    let model = match method {
+        OutlierMethod::Confidence => {
+            let artifact_dir =
+                artifact_dir.context("Scoring by confidence needs a trained model")?;
+            let predictor = Predictor::<B>::load(artifact_dir, device)?;
+            let probabilities = predictor.predict(tracks);
+            Some((predictor.class_names, probabilities))
+        }
+        _ => None,
+    };
+
+    let mut outliers = vec![];
+    for (sublist, track_ids) in store.assignments() {
+        // Unsorted tracks have nothing in common to stand out from
+        if sublist == UNSORTED {
+            continue;
+        }
+        let members: Vec<usize> = track_ids
+            .iter()
+            .filter_map(|x| index_of.get(x.as_str()).copied())
+            .collect();
+        if members.len() < MIN_MEMBERS {
+            continue;
+        }
+        let member_points: Vec<&[f32]> = members.iter().map(|x| points[*x].as_slice()).collect();
+        let scores = match (method, &model) {
+            (OutlierMethod::Mahalanobis, _) => mahalanobis(&member_points),
+            (OutlierMethod::IsolationForest, _) => isolation_forest(&member_points),
+            (OutlierMethod::Confidence, Some((class_names, probabilities))) => {
+                // Sublists the model doesn't know, like ones added after training, can't be scored
+                let Some(class) = class_names.iter().position(|x| *x == sublist) else {
+                    continue;
+                };
+                members
+                    .iter()
+                    .map(|x| 1.0 - probabilities[*x][class])
+                    .collect()
+            }
+            (OutlierMethod::Confidence, None) => unreachable!("the model is loaded above"),
+        };
+
+        let mut scored: Vec<(usize, f32)> = members.into_iter().zip(scores).collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        outliers.extend(scored.into_iter().take(top).map(|(i, score)| {
+            let track = &tracks[i];
+            let source = match store.get(&track.track_id, LabelSource::Manual) {
+                Some(entry) if entry.sublists.contains(&sublist) => LabelSource::Manual,
+                _ => LabelSource::Predicted,
+            };
+            Outlier {
+                sublist: sublist.clone(),
+                track_id: track.track_id.clone(),
+                track_name: track.track_name.clone(),
+                artists: track.artists.clone(),
+                source,
+                score,
+            }
+        }));
+    }
+    Ok(outliers)
+}
+
+pub fn print_outliers(outliers: &[Outlier], format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Json => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(outliers).context("Error in serializing outliers")?
+            );
+        }
+        OutputFormat::Table => {
+            println!(
+                "{:<20} {:<40} {:<30} {:<10} {:>8}",
+                "Sublist", "Song", "Artists", "Source", "Score"
+            );
+            outliers.iter().for_each(|x| {
+                println!(
+                    "{:<20} {:<40} {:<30} {:<10} {:>8.3}",
+                    truncate(&x.sublist, 20),
+                    truncate(&x.track_name, 40),
+                    truncate(&x.artists.join(", "), 30),
+                    format!("{:?}", x.source).to_lowercase(),
+                    x.score
+                )
+            });
+        }
+    }
+    Ok(())
+}
+
+// Pulls the covariance towards the identity, since sublists often have fewer tracks than
+// features and their raw covariance can't be inverted
+const SHRINKAGE: f64 = 0.1;
+
+// Squared Mahalanobis distance of every point to the mean of all of them
+fn mahalanobis(points: &[&[f32]]) -> Vec<f32> {
+    let n = points.len() as f64;
+    let d = points[0].len();
+    let mean: Vec<f64> = (0..d)
+        .map(|f| points.iter().map(|x| x[f] as f64).sum::<f64>() / n)
+        .collect();
+    let centered: Vec<Vec<f64>> = points
+        .iter()
+        .map(|x| {
+            x.iter()
+                .zip(mean.iter())
+                .map(|(x, m)| *x as f64 - m)
+                .collect()
+        })
+        .collect();
+    let mut covariance = vec![vec![0.0; d]; d];
+    for i in 0..d {
+        for j in 0..=i {
+            let c = centered.iter().map(|x| x[i] * x[j]).sum::<f64>() / (n - 1.0);
+            let shrunk = (1.0 - SHRINKAGE) * c + if i == j { SHRINKAGE } else { 0.0 };
+            covariance[i][j] = shrunk;
+            covariance[j][i] = shrunk;
+        }
+    }
+    let lower = cholesky(&covariance);
+    centered
+        .iter()
+        .map(|x| {
+            // Solving L y = x gives the squared distance as |y|^2
+            let mut y = vec![0.0; d];
+            for i in 0..d {
+                let sum: f64 = (0..i).map(|j| lower[i][j] * y[j]).sum();
+                y[i] = (x[i] - sum) / lower[i][i];
+            }
+            y.iter().map(|x| x * x).sum::<f64>() as f32
+        })
+        .collect()
+}
+
+// Lower triangular L with L Lᵀ = matrix, for a symmetric positive definite matrix
+fn cholesky(matrix: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let d = matrix.len();
+    let mut lower = vec![vec![0.0; d]; d];
+    for i in 0..d {
+        for j in 0..=i {
+            let sum: f64 = (0..j).map(|k| lower[i][k] * lower[j][k]).sum();
+            lower[i][j] = match i == j {
+                true => (matrix[i][i] - sum).max(f64::EPSILON).sqrt(),
+                false => (matrix[i][j] - sum) / lower[j][j],
+            };
+        }
+    }
+    lower
+}
+
+const TREES: usize = 100;
+const TREE_SAMPLE: usize = 256;
+
+// Anomaly score of every point from 0 to 1, above 0.5 means it is isolated in fewer random
+// splits than an average point
+fn isolation_forest(points: &[&[f32]]) -> Vec<f32> {
+    let mut rng = StdRng::seed_from_u64(42);
+    let sample_size = TREE_SAMPLE.min(points.len());
+    let max_depth = (sample_size as f32).log2().ceil() as usize;
+    let trees: Vec<Node> = (0..TREES)
+        .map(|_| {
+            let sample: Vec<usize> = (0..points.len())
+                .collect::<Vec<_>>()
+                .choose_multiple(&mut rng, sample_size)
+                .cloned()
+                .collect();
+            build_tree(points, sample, 0, max_depth, &mut rng)
+        })
+        .collect();
+    let normalization = average_path_length(sample_size);
+    points
+        .iter()
+        .map(|x| {
+            let depth = trees
+                .iter()
+                .map(|tree| path_length(tree, x, 0))
+                .sum::<f32>()
+                / TREES as f32;
+            2f32.powf(-depth / normalization)
+        })
+        .collect()
+}
+
+enum Node {
+    Leaf {
+        size: usize,
+    },
+    Split {
+        feature: usize,
+        value: f32,
+        left: Box<Node>,
+        right: Box<Node>,
+    },
+}
+
+fn build_tree(
+    points: &[&[f32]],
+    members: Vec<usize>,
+    depth: usize,
+    max_depth: usize,
+    rng: &mut StdRng,
+) -> Node {
+    if depth >= max_depth || members.len() <= 1 {
+        return Node::Leaf {
+            size: members.len(),
+        };
+    }
+    // Only features that still vary between the members can split them
+    let d = points[0].len();
+    let ranges: Vec<(usize, f32, f32)> = (0..d)
+        .map(|f| {
+            let values = members.iter().map(|x| points[*x][f]);
+            let low = values.clone().fold(f32::INFINITY, f32::min);
+            let high = values.fold(f32::NEG_INFINITY, f32::max);
+            (f, low, high)
+        })
+        .filter(|(_, low, high)| high > low)
+        .collect();
+    let Some((feature, low, high)) = ranges.choose(rng).copied() else {
+        return Node::Leaf {
+            size: members.len(),
+        };
+    };
+    let value = rng.gen_range(low..high);
+    let (left, right): (Vec<usize>, Vec<usize>) = members
+        .into_iter()
+        .partition(|x| points[*x][feature] < value);
+    Node::Split {
+        feature,
+        value,
+        left: Box::new(build_tree(points, left, depth + 1, max_depth, rng)),
+        right: Box::new(build_tree(points, right, depth + 1, max_depth, rng)),
+    }
+}
+
+fn path_length(node: &Node, point: &[f32], depth: usize) -> f32 {
+    match node {
+        // A leaf holding several points stands for the rest of a tree too deep to build
+        Node::Leaf { size } => depth as f32 + average_path_length(*size),
+        Node::Split {
+            feature,
+            value,
+            left,
+            right,
+        } => match point[*feature] < *value {
+            true => path_length(left, point, depth + 1),
+            false => path_length(right, point, depth + 1),
+        },
+    }
+}
+
+// Average path length of an unsuccessful search in a binary search tree of n points
+fn average_path_length(n: usize) -> f32 {
+    match n {
+        0 | 1 => 0.0,
+        2 => 1.0,
+        n => {
+            let n = n as f32;
+            2.0 * ((n - 1.0).ln() + 0.577_215_7) - 2.0 * (n - 1.0) / n
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tight cloud of points around the origin with one point far off in the last place
+    fn planted_outlier() -> Vec<Vec<f32>> {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut points: Vec<Vec<f32>> = (0..40)
+            .map(|_| (0..4).map(|_| rng.gen_range(-1.0..1.0)).collect())
+            .collect();
+        points.push(vec![6.0, -6.0, 6.0, -6.0]);
+        points
+    }
+
+    fn most_unusual(scores: &[f32]) -> usize {
+        scores
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map(|x| x.0)
+            .unwrap()
+    }
+
+    #[test]
+    fn cholesky_factors_the_matrix() {
+        let matrix = vec![
+            vec![4.0, 2.0, 0.4],
+            vec![2.0, 5.0, 1.0],
+            vec![0.4, 1.0, 3.0],
+        ];
+        let lower = cholesky(&matrix);
+        for i in 0..3 {
+            for j in 0..3 {
+                if j > i {
+                    assert_eq!(lower[i][j], 0.0);
+                }
+                let product: f64 = (0..3).map(|k| lower[i][k] * lower[j][k]).sum();
+                assert!((product - matrix[i][j]).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn mahalanobis_finds_the_planted_outlier() {
+        let points = planted_outlier();
+        let points: Vec<&[f32]> = points.iter().map(|x| x.as_slice()).collect();
+        let scores = mahalanobis(&points);
+        assert_eq!(most_unusual(&scores), points.len() - 1);
+        assert!(scores.iter().all(|x| *x >= 0.0));
+    }
+
+    #[test]
+    fn mahalanobis_scales_by_the_spread_of_each_feature() {
+        // The first feature varies a lot, the second hardly, so the same step away from the
+        // mean is far more unusual in the second
+        let mut points: Vec<Vec<f32>> = (0..20)
+            .map(|i| vec![(i as f32 - 9.5) * 2.0, if i % 2 == 0 { 0.1 } else { -0.1 }])
+            .collect();
+        points.push(vec![3.0, 0.0]);
+        points.push(vec![0.0, 3.0]);
+        let points: Vec<&[f32]> = points.iter().map(|x| x.as_slice()).collect();
+        let scores = mahalanobis(&points);
+        assert!(scores[21] > scores[20]);
+    }
+
+    #[test]
+    fn isolation_forest_finds_the_planted_outlier() {
+        let points = planted_outlier();
+        let points: Vec<&[f32]> = points.iter().map(|x| x.as_slice()).collect();
+        let scores = isolation_forest(&points);
+        assert_eq!(most_unusual(&scores), points.len() - 1);
+        assert!(scores[points.len() - 1] > 0.5);
+        assert!(scores.iter().all(|x| (0.0..=1.0).contains(x)));
+    }
+}
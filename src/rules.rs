@@ -0,0 +1,572 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::data_structs::{TrimmedTrack, NUMBER_FEATURES, TEXT_FEATURES};
+
+pub const RULES_FILE: &str = "data/rules.json";
+
+/// Rules over track features, written like
+/// `valence < 0.3 and energy < 0.4`, `year < 1990 or not explicit = true`,
+/// `tempo in 120..130` or `artist = "Daft Punk" or album ~ "live"`.
+///
+/// Numbers are compared with `<`, `<=`, `>`, `>=`, `=` and `!=`, and `in low..high` matches an
+/// inclusive range. Text is matched with `=` (whole value) and `~` (contains), both ignoring case,
+/// and `!=` and `!~` for the opposite. `true` and `false` stand for 1 and 0.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare {
+        feature: String,
+        op: CompareOp,
+        value: f32,
+    },
+    Range {
+        feature: String,
+        low: f32,
+        high: f32,
+    },
+    Text {
+        field: String,
+        op: TextOp,
+        value: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextOp {
+    Equals,
+    Contains,
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(source).with_context(|| format!("Error in rule {source:?}"))?,
+            position: 0,
+        };
+        let expr = parser
+            .or()
+            .and_then(|expr| match parser.peek() {
+                None => Ok(expr),
+                Some(token) => bail!("Unexpected {token} after the end of the rule"),
+            })
+            .with_context(|| format!("Error in rule {source:?}"))?;
+        Ok(expr)
+    }
+
+    pub fn matches(&self, track: &TrimmedTrack) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(track) && b.matches(track),
+            Expr::Or(a, b) => a.matches(track) || b.matches(track),
+            Expr::Not(a) => !a.matches(track),
+            Expr::Compare { feature, op, value } => {
+                let Some(x) = track.number_feature(feature) else {
+                    return false;
+                };
+                match op {
+                    CompareOp::Lt => x < *value,
+                    CompareOp::Le => x <= *value,
+                    CompareOp::Gt => x > *value,
+                    CompareOp::Ge => x >= *value,
+                    CompareOp::Eq => x == *value,
+                    CompareOp::Ne => x != *value,
+                }
+            }
+            Expr::Range { feature, low, high } => track
+                .number_feature(feature)
+                .is_some_and(|x| *low <= x && x <= *high),
+            Expr::Text { field, op, value } => {
+                let value = value.to_lowercase();
+                track
+                    .text_feature(field)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|x| x.to_lowercase())
+                    .any(|x| match op {
+                        TextOp::Equals => x == value,
+                        TextOp::Contains => x.contains(&value),
+                    })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Number(f32),
+    Text(String),
+    Op(&'static str),
+    Open,
+    Close,
+    DotDot,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(x) => write!(f, "{x:?}"),
+            Token::Number(x) => write!(f, "{x}"),
+            Token::Text(x) => write!(f, "\"{x}\""),
+            Token::Op(x) => write!(f, "{x:?}"),
+            Token::Open => write!(f, "\"(\""),
+            Token::Close => write!(f, "\")\""),
+            Token::DotDot => write!(f, "\"..\""),
+        }
+    }
+}
+
+// Longer operators first so "<=" isn't read as "<"
+const OPERATORS: &[&str] = &["<=", ">=", "!=", "!~", "<", ">", "=", "~"];
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let rest: String = chars[i..].iter().take(2).collect();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' || c == ')' {
+            tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            i += 1;
+        } else if rest == ".." {
+            tokens.push(Token::DotDot);
+            i += 2;
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(*op));
+            i += op.len();
+        } else if c == '"' {
+            let Some(end) = chars[i + 1..].iter().position(|x| *x == '"') else {
+                bail!("Unclosed quote");
+            };
+            tokens.push(Token::Text(chars[i + 1..i + 1 + end].iter().collect()));
+            i += end + 2;
+        } else if c.is_ascii_digit()
+            || (c == '-' && chars.get(i + 1).is_some_and(char::is_ascii_digit))
+        {
+            let start = i;
+            i += 1;
+            while i < chars.len()
+                && (chars[i].is_ascii_digit()
+                    // A dot only belongs to the number if a digit follows, "1..2" is a range
+                    || (chars[i] == '.' && chars.get(i + 1).is_some_and(char::is_ascii_digit)))
+            {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            tokens.push(Token::Number(number.parse().context("Invalid number")?));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Word(
+                chars[start..i].iter().collect::<String>().to_lowercase(),
+            ));
+        } else {
+            bail!("Unexpected character {c:?}");
+        }
+    }
+    Ok(tokens)
+}
+
+// Recursive descent, "not" binds tighter than "and", which binds tighter than "or"
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self.peek().cloned().context("Unexpected end of the rule")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        let found = self.peek() == Some(&Token::Word(word.to_string()));
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat_word("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.not()?;
+        while self.eat_word("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        match self.eat_word("not") {
+            true => Ok(Expr::Not(Box::new(self.not()?))),
+            false => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let name = match self.next()? {
+            Token::Open => {
+                let expr = self.or()?;
+                match self.next()? {
+                    Token::Close => return Ok(expr),
+                    token => bail!("Expected \")\", got {token}"),
+                }
+            }
+            Token::Word(name) => name,
+            token => bail!("Expected a feature name, got {token}"),
+        };
+
+        if TEXT_FEATURES.contains(&name.as_str()) {
+            let op = match self.next()? {
+                Token::Op(op) if ["=", "!=", "~", "!~"].contains(&op) => op,
+                token => bail!("Expected =, !=, ~ or !~ after {name}, got {token}"),
+            };
+            let value = match self.next()? {
+                Token::Text(x) | Token::Word(x) => x,
+                token => bail!("Expected quoted text after {name} {op}, got {token}"),
+            };
+            let expr = Expr::Text {
+                field: name,
+                op: match op {
+                    "=" | "!=" => TextOp::Equals,
+                    _ => TextOp::Contains,
+                },
+                value,
+            };
+            return Ok(match op.starts_with('!') {
+                true => Expr::Not(Box::new(expr)),
+                false => expr,
+            });
+        }
+        if !NUMBER_FEATURES.contains(&name.as_str()) {
+            bail!(
+                "Unknown feature {name:?}, expected one of {}",
+                NUMBER_FEATURES
+                    .iter()
+                    .chain(TEXT_FEATURES.iter())
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        if self.eat_word("in") {
+            let low = self.number()?;
+            match self.next()? {
+                Token::DotDot => {}
+                token => bail!("Expected \"..\" in the range of {name}, got {token}"),
+            }
+            let high = self.number()?;
+            return Ok(Expr::Range {
+                feature: name,
+                low,
+                high,
+            });
+        }
+        let op = match self.next()? {
+            Token::Op("<") => CompareOp::Lt,
+            Token::Op("<=") => CompareOp::Le,
+            Token::Op(">") => CompareOp::Gt,
+            Token::Op(">=") => CompareOp::Ge,
+            Token::Op("=") => CompareOp::Eq,
+            Token::Op("!=") => CompareOp::Ne,
+            token => bail!("Expected a comparison or \"in\" after {name}, got {token}"),
+        };
+        Ok(Expr::Compare {
+            feature: name,
+            op,
+            value: self.number()?,
+        })
+    }
+
+    fn number(&mut self) -> Result<f32> {
+        match self.next()? {
+            Token::Number(x) => Ok(x),
+            Token::Word(x) if x == "true" => Ok(1.0),
+            Token::Word(x) if x == "false" => Ok(0.0),
+            token => bail!("Expected a number, got {token}"),
+        }
+    }
+}
+
+/// A rule as written in the config, checked when loaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rule {
+    source: String,
+    expr: Expr,
+}
+
+impl Rule {
+    pub fn matches(&self, track: &TrimmedTrack) -> bool {
+        self.expr.matches(track)
+    }
+}
+
+impl TryFrom<String> for Rule {
+    type Error = anyhow::Error;
+
+    fn try_from(source: String) -> Result<Self> {
+        let expr = Expr::parse(&source)?;
+        Ok(Self { source, expr })
+    }
+}
+
+impl From<Rule> for String {
+    fn from(rule: Rule) -> Self {
+        rule.source
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// Every rule, saved in [`RULES_FILE`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RuleConfig {
+    /// Sublists defined by a rule alone, by name. They get a playlist on apply like learned
+    /// sublists but never need labels or a model.
+    #[serde(default)]
+    pub smart: BTreeMap<String, Rule>,
+    /// Rules a track has to match before a model may put it in a learned sublist, by sublist.
+    #[serde(default)]
+    pub constraints: BTreeMap<String, Rule>,
+}
+
+impl RuleConfig {
+    /// Loads the rules, none if nothing has been saved yet.
+    pub fn load() -> Result<Self> {
+        if !Path::new(RULES_FILE).exists() {
+            return Ok(Self::default());
+        }
+        let file = File::open(RULES_FILE).context("Error in opening rules")?;
+        serde_json::from_reader(BufReader::new(file)).context("Error in reading rules")
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let file = File::create(RULES_FILE).context("Error in creating rules")?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self).context("Error in writing rules")
+    }
+
+    /// Track ids of every smart sublist.
+    pub fn assignments(&self, tracks: &[TrimmedTrack]) -> BTreeMap<String, BTreeSet<String>> {
+        self.smart
+            .iter()
+            .map(|(name, rule)| {
+                let members = tracks
+                    .iter()
+                    .filter(|x| rule.matches(x))
+                    .map(|x| x.track_id.clone())
+                    .collect();
+                (name.clone(), members)
+            })
+            .collect()
+    }
+
+    /// Whether the constraint of `sublist`, if it has one, lets `track` in.
+    pub fn allows(&self, sublist: &str, track: &TrimmedTrack) -> bool {
+        self.constraints
+            .get(sublist)
+            .map_or(true, |rule| rule.matches(track))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compare(feature: &str, op: CompareOp, value: f32) -> Expr {
+        Expr::Compare {
+            feature: feature.to_string(),
+            op,
+            value,
+        }
+    }
+
+    fn error(source: &str) -> String {
+        format!("{:#}", Expr::parse(source).unwrap_err())
+    }
+
+    fn track() -> TrimmedTrack {
+        TrimmedTrack {
+            album_name: "Alive 2007".to_string(),
+            ..TrimmedTrack::fake("a", "One More Time", &["Daft Punk"], 0)
+        }
+        .with_feature("energy", 0.7)
+        .with_feature("valence", 0.2)
+        .with_feature("tempo", 122.0)
+        .with_feature("loudness", -6.0)
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_than_or() {
+        let energy = compare("energy", CompareOp::Gt, 0.5);
+        let valence = compare("valence", CompareOp::Lt, 0.3);
+        let tempo = compare("tempo", CompareOp::Ge, 120.0);
+        assert_eq!(
+            Expr::parse("not energy > 0.5 or valence < 0.3 and tempo >= 120").unwrap(),
+            Expr::Or(
+                Box::new(Expr::Not(Box::new(energy.clone()))),
+                Box::new(Expr::And(
+                    Box::new(valence.clone()),
+                    Box::new(tempo.clone())
+                ))
+            )
+        );
+        assert_eq!(
+            Expr::parse("not (energy > 0.5 or valence < 0.3) and tempo >= 120").unwrap(),
+            Expr::And(
+                Box::new(Expr::Not(Box::new(Expr::Or(
+                    Box::new(energy),
+                    Box::new(valence)
+                )))),
+                Box::new(tempo)
+            )
+        );
+    }
+
+    #[test]
+    fn ranges_and_decimals() {
+        assert_eq!(
+            Expr::parse("tempo in 1..2").unwrap(),
+            Expr::Range {
+                feature: "tempo".to_string(),
+                low: 1.0,
+                high: 2.0
+            }
+        );
+        assert_eq!(
+            Expr::parse("tempo in 1.5..2.5").unwrap(),
+            Expr::Range {
+                feature: "tempo".to_string(),
+                low: 1.5,
+                high: 2.5
+            }
+        );
+        assert_eq!(
+            Expr::parse("tempo = 1.5").unwrap(),
+            compare("tempo", CompareOp::Eq, 1.5)
+        );
+        assert!(Expr::parse("tempo in 1").is_err());
+    }
+
+    #[test]
+    fn negative_numbers() {
+        assert_eq!(
+            Expr::parse("loudness < -5").unwrap(),
+            compare("loudness", CompareOp::Lt, -5.0)
+        );
+        assert_eq!(
+            Expr::parse("loudness in -10..-2.5").unwrap(),
+            Expr::Range {
+                feature: "loudness".to_string(),
+                low: -10.0,
+                high: -2.5
+            }
+        );
+        assert!(Expr::parse("loudness in -10..-2.5")
+            .unwrap()
+            .matches(&track()));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(error(r#"artist = "Daft Punk"#).contains("Unclosed quote"));
+        assert!(error("bpm > 120").contains("Unknown feature \"bpm\""));
+        assert!(error("energy > 0.5 valence").contains("after the end of the rule"));
+        assert!(error("(energy > 0.5").contains("Unexpected end of the rule"));
+        assert!(error("energy ~ 0.5").contains("Expected a comparison"));
+        assert!(error("artist < 3").contains("Expected =, !=, ~ or !~"));
+        assert!(error("energy > 0.5 & valence < 0.3").contains("Unexpected character '&'"));
+    }
+
+    #[test]
+    fn negated_operators() {
+        let track = track();
+        assert!(!Expr::parse("energy != 0.7").unwrap().matches(&track));
+        assert!(Expr::parse("energy != 0.5").unwrap().matches(&track));
+        assert!(!Expr::parse(r#"artist != "Daft Punk""#)
+            .unwrap()
+            .matches(&track));
+        assert!(Expr::parse(r#"artist != "Daft""#).unwrap().matches(&track));
+        assert!(!Expr::parse(r#"album !~ "live""#).unwrap().matches(&track));
+        assert!(Expr::parse(r#"album !~ "studio""#).unwrap().matches(&track));
+    }
+
+    #[test]
+    fn text_ignores_case() {
+        let track = track();
+        assert!(Expr::parse(r#"artist = "daft punk""#)
+            .unwrap()
+            .matches(&track));
+        assert!(Expr::parse(r#"NAME ~ "MORE""#).unwrap().matches(&track));
+        assert!(Expr::parse("album ~ ALIVE").unwrap().matches(&track));
+        assert!(!Expr::parse(r#"artist = "Daft""#).unwrap().matches(&track));
+    }
+
+    #[test]
+    fn numbers_and_booleans() {
+        let track = track();
+        assert!(Expr::parse("energy > 0.5 and valence <= 0.2")
+            .unwrap()
+            .matches(&track));
+        assert!(Expr::parse("tempo in 120..130 and explicit = false")
+            .unwrap()
+            .matches(&track));
+        assert!(!Expr::parse("explicit = true").unwrap().matches(&track));
+    }
+
+    #[test]
+    fn rule_serde_round_trip() {
+        let config: RuleConfig =
+            serde_json::from_str(r#"{"smart": {"Calm": "energy < 0.4 and not explicit = true"}}"#)
+                .unwrap();
+        let rule = &config.smart["Calm"];
+        assert_eq!(rule.to_string(), "energy < 0.4 and not explicit = true");
+        assert!(config.constraints.is_empty());
+
+        let json = serde_json::to_string(&config).unwrap();
+        let reloaded: RuleConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(&reloaded.smart["Calm"], rule);
+
+        assert!(serde_json::from_str::<Rule>(r#""energy <""#).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+pub const SESSION_FILE: &str = "data/session.json";
+
+/// A labelling session in progress. It is saved after every action so an interrupted session can
+/// be resumed where it stopped, unless it was started with [`LabelSession::unsaved`]. Tracks are
+/// kept by id since the motherlist can change in between.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LabelSession {
+    pub sublists: Vec<String>,
+    /// Track ids still to be shown, the current track first.
+    queue: VecDeque<String>,
+    /// Labels given during the session by track id, numbered from 1 like in
+    /// [`crate::labels::get_labels`].
+    labels: BTreeMap<String, Vec<u32>>,
+    /// Every labelled track with the labels it had before, latest last, so labels can be undone.
+    history: Vec<(String, Vec<u32>)>,
+    /// Where the session is saved, `None` for one that can't be resumed.
+    #[serde(skip)]
+    file: Option<PathBuf>,
+}
+
+impl LabelSession {
+    pub fn new(sublists: &[String], queue: Vec<String>) -> Self {
+        Self {
+            sublists: sublists.to_vec(),
+            queue: queue.into(),
+            labels: BTreeMap::new(),
+            history: vec![],
+            file: Some(PathBuf::from(SESSION_FILE)),
+        }
+    }
+
+    /// A session that is never saved, for labelling that only makes sense as part of another
+    /// command, like sorting the tracks of a split sublist. It leaves the saved session alone.
+    pub fn unsaved(sublists: &[String], queue: Vec<String>) -> Self {
+        Self {
+            file: None,
+            ..Self::new(sublists, queue)
+        }
+    }
+
+    /// Saves the session to `file` instead of [`SESSION_FILE`].
+    pub fn with_file(mut self, file: &Path) -> Self {
+        self.file = Some(file.to_path_buf());
+        self
+    }
+
+    /// Loads the saved session, if there is one for the same sublists.
+    pub fn load(sublists: &[String]) -> Result<Option<Self>> {
+        Self::load_from(Path::new(SESSION_FILE), sublists)
+    }
+
+    pub fn load_from(path: &Path, sublists: &[String]) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = File::open(path).context("Error in opening labelling session")?;
+        let session: Self = serde_json::from_reader(BufReader::new(file))
+            .context("Error in reading labelling session")?;
+        Ok((session.sublists == sublists).then(|| session.with_file(path)))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.file else {
+            return Ok(());
+        };
+        let file = File::create(path).context("Error in creating labelling session")?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)
+            .context("Error in writing labelling session")
+    }
+
+    /// Deletes the saved session once every track has been seen.
+    pub fn discard(&self) -> Result<()> {
+        match &self.file {
+            Some(path) if path.exists() => {
+                std::fs::remove_file(path).context("Error in deleting labelling session")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn current(&self) -> Option<&String> {
+        self.queue.front()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn labels(&self) -> &BTreeMap<String, Vec<u32>> {
+        &self.labels
+    }
+
+    /// Gives the current track `labels` and moves on to the next one. `previous` is what the
+    /// track was labelled before, restored by [`LabelSession::undo`].
+    pub fn label(&mut self, previous: Vec<u32>, labels: Vec<u32>) {
+        if let Some(track_id) = self.queue.pop_front() {
+            self.history.push((track_id.clone(), previous));
+            self.labels.insert(track_id, labels);
+        }
+    }
+
+    /// Moves the current track to the back of the queue.
+    pub fn skip(&mut self) {
+        if let Some(track_id) = self.queue.pop_front() {
+            self.queue.push_back(track_id);
+        }
+    }
+
+    /// Takes back the last label and makes its track the current one again. Returns the track id
+    /// and the labels it is back to.
+    pub fn undo(&mut self) -> Option<(String, Vec<u32>)> {
+        let (track_id, previous) = self.history.pop()?;
+        self.labels.insert(track_id.clone(), previous.clone());
+        self.jump_to(&track_id);
+        Some((track_id, previous))
+    }
+
+    /// Makes any track, labelled or not, the current one.
+    pub fn jump_to(&mut self, track_id: &str) {
+        self.queue.retain(|x| x != track_id);
+        self.queue.push_front(track_id.to_string());
+    }
+
+    /// Drops tracks that are no longer in the motherlist from a resumed session.
+    pub fn retain_tracks(&mut self, keep: impl Fn(&str) -> bool) {
+        self.queue.retain(|x| keep(x));
+        self.labels.retain(|x, _| keep(x));
+        self.history.retain(|(x, _)| keep(x));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn session() -> LabelSession {
+        LabelSession::unsaved(&names(&["Rock", "Jazz"]), names(&["t1", "t2", "t3"]))
+    }
+
+    #[test]
+    fn skip_moves_the_track_to_the_back() {
+        let mut session = session();
+        session.skip();
+        assert_eq!(session.current().unwrap(), "t2");
+        assert_eq!(session.remaining(), 3);
+        session.label(vec![], vec![1]);
+        session.label(vec![], vec![2]);
+        assert_eq!(session.current().unwrap(), "t1");
+    }
+
+    #[test]
+    fn undo_restores_the_previous_labels() {
+        let mut session = session();
+        session.label(vec![2], vec![1]);
+        session.label(vec![], vec![2]);
+        assert_eq!(session.undo(), Some(("t2".to_string(), vec![])));
+        assert_eq!(session.current().unwrap(), "t2");
+        assert_eq!(session.undo(), Some(("t1".to_string(), vec![2])));
+        assert_eq!(session.current().unwrap(), "t1");
+        assert_eq!(session.labels()["t1"], vec![2]);
+        assert_eq!(session.remaining(), 3);
+        assert_eq!(session.undo(), None);
+    }
+
+    #[test]
+    fn jump_to_makes_a_track_current_once() {
+        let mut session = session();
+        session.jump_to("t3");
+        assert_eq!(session.current().unwrap(), "t3");
+        assert_eq!(session.remaining(), 3);
+
+        // A labelled track comes back to be relabelled
+        session.label(vec![], vec![1]);
+        session.jump_to("t3");
+        assert_eq!(session.current().unwrap(), "t3");
+        assert_eq!(session.remaining(), 3);
+    }
+
+    #[test]
+    fn retain_tracks_drops_removed_tracks() {
+        let mut session = session();
+        session.label(vec![], vec![1]);
+        session.retain_tracks(|x| x != "t1" && x != "t3");
+        assert_eq!(session.current().unwrap(), "t2");
+        assert_eq!(session.remaining(), 1);
+        assert!(session.labels().is_empty());
+        assert_eq!(session.undo(), None);
+    }
+
+    #[test]
+    fn saved_session_resumes_for_the_same_sublists() {
+        let path = std::env::temp_dir().join(format!("session_test_{}.json", std::process::id()));
+        let mut session = session().with_file(&path);
+        session.label(vec![], vec![2]);
+        session.save().unwrap();
+
+        let mut resumed = LabelSession::load_from(&path, &session.sublists)
+            .unwrap()
+            .unwrap();
+        assert_eq!(resumed.current().unwrap(), "t2");
+        assert_eq!(resumed.labels(), session.labels());
+        assert_eq!(resumed.undo(), Some(("t1".to_string(), vec![])));
+        // Other sublists start a new session
+        assert!(LabelSession::load_from(&path, &names(&["Rock"]))
+            .unwrap()
+            .is_none());
+
+        resumed.discard().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn unsaved_session_leaves_no_file() {
+        let session = session();
+        session.save().unwrap();
+        session.discard().unwrap();
+        assert!(session.file.is_none());
+    }
+}
@@ -0,0 +1,322 @@
+use anyhow::{bail, Context, Result};
+
+use crate::apply::AppliedState;
+use crate::artifacts::ArtifactDir;
+use crate::client::PlaylistClient;
+use crate::data_structs::TrimmedTrack;
+use crate::dataset;
+use crate::hierarchy::Hierarchy;
+use crate::label_store::{LabelSource, LabelStore};
+use crate::labels;
+use crate::rules::RuleConfig;
+use crate::thresholds::{Thresholds, UNSORTED};
+
+/// Everything that refers to sublists by name or by position. Edits go through here so the
+/// sublists file, the label store, the training database and the applied playlists never
+/// disagree. Class ids are positions in `sublists`, so removing a sublist shifts the ids after it
+/// and the database is rebuilt on save.
+pub struct Sublists {
+    pub names: Vec<String>,
+    pub hierarchy: Hierarchy,
+    pub store: LabelStore,
+    pub applied: AppliedState,
+    pub rules: RuleConfig,
+}
+
+impl Sublists {
+    pub fn load() -> Result<Self> {
+        Ok(Self {
+            names: dataset::read_sublists()?,
+            hierarchy: Hierarchy::load()?,
+            store: LabelStore::load()?,
+            applied: AppliedState::load()?,
+            rules: RuleConfig::load()?,
+        })
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        self.hierarchy.retain(&self.names);
+        dataset::write_sublists(&self.names)?;
+        self.hierarchy.save()?;
+        self.store.save().context("Error in saving labels")?;
+        self.applied.save()?;
+        self.rules.save()?;
+        dataset::rebuild_labels(&self.store).context("Error in relabelling the training set")
+    }
+
+    fn position(&self, name: &str) -> Result<usize> {
+        match self.names.iter().position(|x| x == name) {
+            Some(i) => Ok(i),
+            None => bail!("There is no sublist named {name:?}"),
+        }
+    }
+
+    fn check_new_name(&self, name: &str) -> Result<()> {
+        if name.trim().is_empty() || name == UNSORTED {
+            bail!("{name:?} can't be used as a sublist name");
+        }
+        if self.names.iter().any(|x| x == name) || self.rules.smart.contains_key(name) {
+            bail!("There already is a sublist named {name:?}");
+        }
+        Ok(())
+    }
+
+    /// Creates a top level sublist and labels `track_ids` with it, on top of their other
+    /// sublists.
+    pub fn add(&mut self, name: &str, track_ids: &[String]) -> Result<()> {
+        self.check_new_name(name)?;
+        self.names.push(name.to_string());
+        for track_id in track_ids {
+            self.store.record_manual_edit(track_id, name, true);
+        }
+        Ok(())
+    }
+
+    /// Renames a sublist and its Spotify playlist, if it has been applied. The class id stays
+    /// the same, so trained models only need their class names updated.
+    pub async fn rename<C: PlaylistClient>(
+        &mut self,
+        client: Option<&C>,
+        from: &str,
+        to: &str,
+    ) -> Result<()> {
+        let index = self.position(from)?;
+        self.check_new_name(to)?;
+        if let Some(playlist) = self.applied.playlists.remove(from) {
+            if let Some(client) = client {
+                client.rename_playlist(&playlist.playlist_id, to).await?;
+            }
+            self.applied.playlists.insert(to.to_string(), playlist);
+        }
+        self.names[index] = to.to_string();
+        self.hierarchy.rename(from, to);
+        if let Some(rule) = self.rules.constraints.remove(from) {
+            self.rules.constraints.insert(to.to_string(), rule);
+        }
+        self.store.rename_sublist(from, to);
+        Ok(())
+    }
+
+    /// Nests a sublist under `parent`, or makes it a top level sublist when `parent` is `None`.
+    /// Labels stay as they are, a track labelled with a nested sublist counts as being in every
+    /// sublist above it.
+    pub fn nest(&mut self, name: &str, parent: Option<&str>) -> Result<()> {
+        self.position(name)?;
+        if let Some(parent) = parent {
+            self.position(parent)?;
+        }
+        self.hierarchy.set_parent(name, parent)
+    }
+
+    /// Deletes a sublist. Its tracks lose that label, the ones left without any label go back
+    /// to being unlabelled, and sublists nested in it move up a level. The Spotify playlist is
+    /// left alone but no longer managed. Returns the number of tracks that were manually
+    /// labelled with it.
+    pub fn delete(&mut self, name: &str) -> Result<usize> {
+        let index = self.position(name)?;
+        self.names.remove(index);
+        self.hierarchy.remove(name);
+        self.rules.constraints.remove(name);
+        self.applied.playlists.remove(name);
+        Ok(self.store.remove_sublist(name).len())
+    }
+
+    /// Moves every track and nested sublist of `from` into `into` and deletes `from`. Returns the
+    /// number of tracks `into` has gained or already shared with `from`.
+    pub fn merge(&mut self, from: &str, into: &str) -> Result<usize> {
+        if from == into {
+            bail!("Can't merge {from:?} into itself");
+        }
+        let index = self.position(from)?;
+        self.position(into)?;
+        if self.hierarchy.ancestors(into).contains(&from) {
+            bail!("Can't merge {from:?} into {into:?} since it is nested in it");
+        }
+        let moved = self
+            .store
+            .entries
+            .iter()
+            .filter(|x| x.source == LabelSource::Manual && x.sublists.iter().any(|x| x == from))
+            .count();
+        self.names.remove(index);
+        self.applied.playlists.remove(from);
+        self.rules.constraints.remove(from);
+        let children: Vec<String> = self.hierarchy.children(from).map(String::from).collect();
+        self.hierarchy.remove(from);
+        for child in children {
+            self.hierarchy.set_parent(&child, Some(into))?;
+        }
+        self.store.rename_sublist(from, into);
+        Ok(moved)
+    }
+
+    /// Replaces a sublist with `parts` and returns the tracks that were manually labelled with
+    /// it, which have to be sorted into the parts again, see [`Sublists::relabel`]. The parts
+    /// take its place in the tree and sublists nested in it move up a level.
+    pub fn split(&mut self, name: &str, parts: &[String]) -> Result<Vec<String>> {
+        if parts.len() < 2 {
+            bail!("A sublist has to be split into at least two sublists");
+        }
+        let index = self.position(name)?;
+        for (i, part) in parts.iter().enumerate() {
+            // A part may keep the name of the sublist it comes from
+            if part != name {
+                self.check_new_name(part)?;
+            }
+            if parts[..i].contains(part) {
+                bail!("{part:?} is given twice");
+            }
+        }
+        self.names.remove(index);
+        self.names.extend(parts.iter().cloned());
+        let parent = self.hierarchy.parent(name).map(String::from);
+        self.hierarchy.remove(name);
+        for part in parts {
+            self.hierarchy.set_parent(part, parent.as_deref())?;
+        }
+        self.applied.playlists.remove(name);
+        self.rules.constraints.remove(name);
+        Ok(self.store.remove_sublist(name))
+    }
+
+    /// Labelling session over just `track_ids` and just the sublists in `parts`, adding the
+    /// chosen parts to the other sublists each track is in. Returns the number of tracks
+    /// labelled, the rest stay out of every part.
+    pub async fn relabel(
+        &mut self,
+        track_ids: &[String],
+        parts: &[String],
+        tracks: &[TrimmedTrack],
+    ) -> Result<usize> {
+        let tracks: Vec<TrimmedTrack> = tracks
+            .iter()
+            .filter(|x| track_ids.contains(&x.track_id))
+            .cloned()
+            .collect();
+        // A one-off session, an unfinished one of the label command stays resumable
+        let labels =
+            labels::get_labels(parts, &tracks, vec![vec![]; tracks.len()], None, false).await?;
+
+        let mut labelled = 0;
+        for (track, label) in tracks.iter().zip(labels.iter()) {
+            if label.is_empty() {
+                continue;
+            }
+            labels::sublist_names(parts, label)
+                .into_iter()
+                .for_each(|part| self.store.record_manual_edit(&track.track_id, part, true));
+            labelled += 1;
+        }
+        Ok(labelled)
+    }
+}
+
+/// Renames a class of a trained model and its tuned threshold, so the model keeps predicting
+/// the sublist after [`Sublists::rename`]. Other edits change the classes themselves and need a
+/// new training run.
+pub fn rename_in_model(artifact_dir: &ArtifactDir, from: &str, to: &str) -> Result<()> {
+    let mut class_names = artifact_dir.load_class_names()?;
+    class_names
+        .iter_mut()
+        .filter(|x| x.as_str() == from)
+        .for_each(|x| *x = to.to_string());
+    artifact_dir.save_class_names(&class_names)?;
+
+    let mut thresholds = Thresholds::load(artifact_dir)?;
+    if let Some(threshold) = thresholds.per_sublist.remove(from) {
+        thresholds.per_sublist.insert(to.to_string(), threshold);
+        thresholds.save(artifact_dir)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|x| x.to_string()).collect()
+    }
+
+    // Electronic > House > Deep House, and Jazz on its own
+    fn sublists(labels: &[(&str, Vec<&str>)]) -> Sublists {
+        let mut hierarchy = Hierarchy::default();
+        hierarchy.set_parent("House", Some("Electronic")).unwrap();
+        hierarchy.set_parent("Deep House", Some("House")).unwrap();
+        let mut store = LabelStore::default();
+        labels
+            .iter()
+            .for_each(|(track_id, sublists)| store.set_manual(track_id, names(sublists)));
+        Sublists {
+            names: names(&["Electronic", "House", "Deep House", "Jazz"]),
+            hierarchy,
+            store,
+            applied: AppliedState::default(),
+            rules: RuleConfig::default(),
+        }
+    }
+
+    #[test]
+    fn split_can_keep_the_original_name() {
+        let mut sublists = sublists(&[("a", vec!["House"]), ("b", vec!["House", "Jazz"])]);
+
+        let to_relabel = sublists
+            .split("House", &names(&["House", "Tech House"]))
+            .unwrap();
+
+        assert_eq!(to_relabel, names(&["a", "b"]));
+        assert_eq!(
+            sublists.names,
+            names(&["Electronic", "Deep House", "Jazz", "House", "Tech House"])
+        );
+        // The parts take the place of the sublist, its children move up a level
+        assert_eq!(sublists.hierarchy.parent("House"), Some("Electronic"));
+        assert_eq!(sublists.hierarchy.parent("Tech House"), Some("Electronic"));
+        assert_eq!(sublists.hierarchy.parent("Deep House"), Some("Electronic"));
+        // Tracks have to be sorted into the parts again
+        assert!(sublists.store.get("a", LabelSource::Manual).is_none());
+        assert_eq!(sublists.store.sublists_of("b").unwrap(), names(&["Jazz"]));
+    }
+
+    #[test]
+    fn split_rejects_bad_parts() {
+        let mut sublists = sublists(&[]);
+        assert!(sublists.split("House", &names(&["House"])).is_err());
+        assert!(sublists
+            .split("House", &names(&["Jazz", "Tech House"]))
+            .is_err());
+        assert!(sublists.split("House", &names(&["Acid", "Acid"])).is_err());
+        assert!(sublists
+            .split("House", &names(&["Acid", UNSORTED]))
+            .is_err());
+        assert_eq!(sublists.names.len(), 4);
+    }
+
+    #[test]
+    fn merge_moves_tracks_and_children() {
+        let mut sublists = sublists(&[
+            ("a", vec!["House"]),
+            ("b", vec!["House", "Jazz"]),
+            ("c", vec!["Jazz"]),
+        ]);
+
+        assert_eq!(sublists.merge("House", "Jazz").unwrap(), 2);
+
+        assert_eq!(sublists.names, names(&["Electronic", "Deep House", "Jazz"]));
+        assert_eq!(sublists.hierarchy.parent("Deep House"), Some("Jazz"));
+        for track_id in ["a", "b", "c"] {
+            assert_eq!(
+                sublists.store.sublists_of(track_id).unwrap(),
+                names(&["Jazz"])
+            );
+        }
+    }
+
+    #[test]
+    fn merge_rejects_merging_into_a_descendant() {
+        let mut sublists = sublists(&[]);
+        assert!(sublists.merge("House", "House").is_err());
+        assert!(sublists.merge("Electronic", "Deep House").is_err());
+        assert!(sublists.merge("House", "Acid").is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use burn::data::dataset::Dataset;
+use burn::tensor::backend::Backend;
+use serde::{Deserialize, Serialize};
+
+use crate::artifacts::ArtifactDir;
+use crate::dataset::SublistDataset;
+use crate::inference::Predictor;
+
+/// Name of the bucket tracks go in when no sublist is confident enough.
+pub const UNSORTED: &str = "Unsorted";
+
+/// Threshold of every sublist until they are tuned. A track goes in a sublist it more likely
+/// belongs to than not, for single-label models that is more likely than all others together,
+/// and otherwise it is unsorted.
+pub const DEFAULT_THRESHOLD: f32 = 0.5;
+
+/// Minimum confidence a prediction needs before a track is put in its sublist.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Thresholds {
+    /// Used for every sublist without its own threshold.
+    pub global: f32,
+    #[serde(default)]
+    pub per_sublist: BTreeMap<String, f32>,
+}
+
+impl Thresholds {
+    pub fn for_sublist(&self, sublist: &str) -> f32 {
+        self.per_sublist
+            .get(sublist)
+            .copied()
+            .unwrap_or(self.global)
+    }
+
+    /// Picks the most likely class if it clears its threshold, `None` means the track is unsorted.
+    pub fn assign(&self, class_names: &[String], probabilities: &[f32]) -> Option<usize> {
+        let (best, confidence) = probabilities
+            .iter()
+            .cloned()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        (confidence >= self.for_sublist(&class_names[best])).then_some(best)
+    }
+
+    /// Picks every class that clears its threshold, for multi-label models whose probabilities
+    /// are independent. Empty means the track is unsorted.
+    pub fn assign_all(&self, class_names: &[String], probabilities: &[f32]) -> Vec<usize> {
+        probabilities
+            .iter()
+            .enumerate()
+            .filter(|(class, p)| **p >= self.for_sublist(&class_names[*class]))
+            .map(|(class, _)| class)
+            .collect()
+    }
+
+    /// Loads the thresholds saved in the artifact directory, [`DEFAULT_THRESHOLD`] for every
+    /// sublist if there are none.
+    pub fn load(artifact_dir: &ArtifactDir) -> Result<Self> {
+        let path = artifact_dir.path().join("thresholds.json");
+        if !Path::new(&path).exists() {
+            return Ok(Self {
+                global: DEFAULT_THRESHOLD,
+                ..Self::default()
+            });
+        }
+        let file = File::open(path).context("Error in opening thresholds file")?;
+        serde_json::from_reader(BufReader::new(file)).context("Error in reading thresholds")
+    }
+
+    pub fn save(&self, artifact_dir: &ArtifactDir) -> Result<()> {
+        let file = File::create(artifact_dir.path().join("thresholds.json"))
+            .context("Error in creating thresholds file")?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)
+            .context("Error in writing thresholds")
+    }
+}
+
+/// Chooses, for every sublist, the lowest threshold at which predictions on the validation split
+/// reach `target_precision`. Sublists that never reach it get a threshold of 1 so nothing is put
+/// in them automatically.
+pub fn tune<B: Backend>(
+    artifact_dir: &ArtifactDir,
+    device: B::Device,
+    target_precision: f32,
+) -> Result<Thresholds> {
+    let predictor = Predictor::<B>::load(artifact_dir, device)?;
+    // The tracks held out during training, labels added since then can't leak into tuning
+    let validation_tracks = artifact_dir
+        .load_validation_tracks()
+        .context("Error in loading the validation split of the training run")?;
+    let dataset_valid = SublistDataset::labelled()
+        .context("Error in loading labelled tracks")?
+        .only(&validation_tracks);
+    let items: Vec<_> = dataset_valid.iter().collect();
+    let tracks: Vec<_> = items.iter().map(|item| item.track.clone()).collect();
+    let probabilities = predictor.predict(&tracks);
+
+    // (confidence, correct) of every validation prediction, grouped by predicted class. A
+    // multi-label model predicts every class for every track, so all of them are candidates.
+    let mut by_class: Vec<Vec<(f32, bool)>> = vec![Vec::new(); predictor.class_names.len()];
+    items
+        .iter()
+        .zip(probabilities.iter())
+        .for_each(|(item, probabilities)| match predictor.multi_label {
+            true => probabilities
+                .iter()
+                .enumerate()
+                .for_each(|(class, p)| by_class[class].push((*p, item.labels.contains(&class)))),
+            false => {
+                if let Some((best, confidence)) = probabilities
+                    .iter()
+                    .cloned()
+                    .enumerate()
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+                {
+                    by_class[best].push((confidence, best == item.label));
+                }
+            }
+        });
+
+    let per_sublist = predictor
+        .class_names
+        .iter()
+        .zip(by_class.iter_mut())
+        .map(|(name, predictions)| {
+            predictions.sort_by(|a, b| a.0.total_cmp(&b.0));
+            // Raising the threshold to predictions[i].0 keeps predictions[i..]
+            let threshold = (0..predictions.len())
+                .find(|&i| {
+                    let kept = &predictions[i..];
+                    let correct = kept.iter().filter(|(_, correct)| *correct).count();
+                    correct as f32 / kept.len() as f32 >= target_precision
+                })
+                .map(|i| predictions[i].0)
+                .unwrap_or(1.0);
+            (name.clone(), threshold)
+        })
+        .collect();
+
+    Ok(Thresholds {
+        global: 0.0,
+        per_sublist,
+    })
+}
@@ -0,0 +1,494 @@
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use burn::{
+    config::Config,
+    data::{
+        dataloader::{batcher::Batcher, DataLoader, DataLoaderBuilder},
+        dataset::Dataset,
+    },
+    module::{AutodiffModule, Module},
+    optim::AdamConfig,
+    record::CompactRecorder,
+    tensor::{backend::AutodiffBackend, ElementConversion},
+    train::{
+        checkpoint::{CheckpointingAction, CheckpointingStrategy},
+        metric::{store::EventStoreClient, AccuracyMetric, LossMetric},
+        ClassificationOutput, LearnerBuilder, TrainStep, ValidStep,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::artifacts::ArtifactDir;
+use crate::batcher::{FusionBatcher, LabelSets, SegmentSequenceBatcher, SongClassificationBatcher};
+use crate::dataset::{SublistDataset, TrackClassificationDataset, TrackClassificationItem};
+use crate::hierarchy::Hierarchy;
+use crate::model::{
+    FusionBranches, FusionConfig, MlpClassifierConfig, SegmentTransformerConfig,
+    TextTransformerConfig,
+};
+use crate::normalizer::{NormalizationKind, Normalizer};
+use crate::tokenizer::{BertCasedTokenizer, Tokenizer};
+
+/// Which classifier the train command fits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum ModelKind {
+    /// MLP over the global audio features
+    Mlp,
+    /// Transformer encoder over the segment pitch/timbre sequences
+    Sequence,
+    /// Transformer encoder over the track, artist and album names
+    Text,
+    /// Concatenation of the feature, sequence and text branches
+    Fusion,
+}
+
+#[derive(Config)]
+pub struct TrainingConfig {
+    #[config(default = "ModelKind::Mlp")]
+    pub model: ModelKind,
+    #[config(default = "MlpClassifierConfig::new()")]
+    pub mlp: MlpClassifierConfig,
+    #[config(default = "SegmentTransformerConfig::new()")]
+    pub sequence: SegmentTransformerConfig,
+    #[config(default = "TextTransformerConfig::new()")]
+    pub text: TextTransformerConfig,
+    #[config(default = "FusionConfig::new()")]
+    pub fusion: FusionConfig,
+    pub optimizer: AdamConfig,
+    #[config(default = 50)]
+    pub num_epochs: usize,
+    #[config(default = 16)]
+    pub batch_size: usize,
+    #[config(default = 1)]
+    pub num_workers: usize,
+    #[config(default = 42)]
+    pub seed: u64,
+    #[config(default = 1.0e-3)]
+    pub learning_rate: f64,
+    /// Fraction of the labelled tracks held out to compute validation metrics.
+    #[config(default = 0.2)]
+    pub valid_ratio: f64,
+    #[config(default = 64)]
+    pub max_seq_length: usize,
+    /// How the audio features are rescaled, fitted on the training split only.
+    #[config(default = "NormalizationKind::ZScore")]
+    pub normalization: NormalizationKind,
+    /// Save a checkpoint that training can be resumed from every this many epochs.
+    #[config(default = 1)]
+    pub checkpoint_every: usize,
+    /// Number of the latest checkpoints kept on disk, older ones are deleted.
+    #[config(default = 2)]
+    pub keep_checkpoints: usize,
+    /// Train one independent output per sublist so a track can be put in several of them.
+    #[config(default = false)]
+    pub multi_label: bool,
+}
+
+impl Default for TrainingConfig {
+    fn default() -> Self {
+        Self::new(AdamConfig::new())
+    }
+}
+
+impl TrainingConfig {
+    /// The branch configs the fusion model is built from.
+    pub fn fusion_branches(&self) -> FusionBranches {
+        FusionBranches {
+            mlp: &self.mlp,
+            sequence: &self.sequence,
+            text: &self.text,
+            max_seq_length: self.max_seq_length,
+        }
+    }
+}
+
+/// Metrics of the trained model over the whole validation split.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ValidationReport {
+    /// Share of tracks whose sublists are all predicted right: the top class of a single-label
+    /// model, or every class above 0.5 of a multi-label one.
+    pub accuracy: f64,
+    pub loss: f64,
+}
+
+/// Trains the configured model and writes it to the artifact directory. With `resume` training
+/// continues from the latest checkpoint in that directory instead of starting over.
+pub fn train<B: AutodiffBackend>(
+    artifact_dir: &ArtifactDir,
+    mut config: TrainingConfig,
+    device: B::Device,
+    resume: bool,
+) -> Result<ValidationReport> {
+    let checkpoint = match resume {
+        true => Some(
+            artifact_dir
+                .latest_checkpoint()
+                .context("No checkpoint to resume training from")?,
+        ),
+        false => None,
+    };
+    let dataset = SublistDataset::labelled().context("Error in loading labelled tracks")?;
+    let hierarchy = Hierarchy::load()?;
+    // A single-label model would only ever learn the first sublist of these tracks, and can't
+    // put a track in a nested sublist and its parent at once
+    if !config.multi_label && dataset.has_multiple_labels() {
+        println!("Some tracks are in several sublists, training a multi-label model");
+        config.multi_label = true;
+    } else if !config.multi_label && !hierarchy.is_flat() {
+        println!("Some sublists are nested, training a multi-label model");
+        config.multi_label = true;
+    }
+    artifact_dir.create()?;
+    artifact_dir.save_config(&config)?;
+    B::seed(config.seed);
+
+    let n_classes = dataset.number_of_classes();
+    let class_names: Vec<String> = (0..n_classes).map(|i| dataset.class_name(i)).collect();
+    artifact_dir.save_class_names(&class_names)?;
+    artifact_dir.save_hierarchy(&hierarchy)?;
+    let (dataset_train, dataset_valid) = dataset.split(config.valid_ratio, config.seed);
+    artifact_dir.save_validation_tracks(
+        &dataset_valid
+            .iter()
+            .map(|item| item.track.track_id)
+            .collect::<Vec<_>>(),
+    )?;
+    let tokenizer = Arc::new(BertCasedTokenizer::default());
+    // Fitted on the training split only so validation tracks don't leak into the statistics
+    let features: Vec<Vec<f32>> = dataset_train
+        .iter()
+        .map(|item| item.track.feature_vector())
+        .collect();
+    let normalizer = Arc::new(
+        Normalizer::fit(config.normalization, &features)
+            .context("Error in fitting feature normalizer")?,
+    );
+    artifact_dir.save_normalizer(&normalizer)?;
+
+    let song_batcher = |device: &B::Device| {
+        SongClassificationBatcher::<B>::new(
+            tokenizer.clone(),
+            normalizer.clone(),
+            device.clone(),
+            config.max_seq_length,
+            n_classes,
+        )
+    };
+    let song_batcher_valid = |device: &B::Device| {
+        SongClassificationBatcher::<B::InnerBackend>::new(
+            tokenizer.clone(),
+            normalizer.clone(),
+            device.clone(),
+            config.max_seq_length,
+            n_classes,
+        )
+    };
+    let sequence_batcher = |device: &B::Device| {
+        SegmentSequenceBatcher::<B>::new(
+            device.clone(),
+            config.sequence.max_segments,
+            config.sequence.stride,
+            n_classes,
+        )
+    };
+    let sequence_batcher_valid = |device: &B::Device| {
+        SegmentSequenceBatcher::<B::InnerBackend>::new(
+            device.clone(),
+            config.sequence.max_segments,
+            config.sequence.stride,
+            n_classes,
+        )
+    };
+
+    match config.model {
+        ModelKind::Mlp => {
+            let (dataloader_train, dataloader_valid) = dataloaders(
+                &config,
+                song_batcher(&device),
+                song_batcher_valid(&device),
+                dataset_train,
+                dataset_valid,
+            );
+            let model = config.mlp.init::<B>(n_classes, config.multi_label);
+            fit(
+                artifact_dir,
+                &config,
+                device,
+                checkpoint,
+                model,
+                dataloader_train,
+                dataloader_valid,
+            )
+        }
+        ModelKind::Text => {
+            let (dataloader_train, dataloader_valid) = dataloaders(
+                &config,
+                song_batcher(&device),
+                song_batcher_valid(&device),
+                dataset_train,
+                dataset_valid,
+            );
+            let model = config.text.init::<B>(
+                n_classes,
+                tokenizer.vocab_size(),
+                config.max_seq_length,
+                config.multi_label,
+            );
+            fit(
+                artifact_dir,
+                &config,
+                device,
+                checkpoint,
+                model,
+                dataloader_train,
+                dataloader_valid,
+            )
+        }
+        ModelKind::Sequence => {
+            let (dataloader_train, dataloader_valid) = dataloaders(
+                &config,
+                sequence_batcher(&device),
+                sequence_batcher_valid(&device),
+                dataset_train,
+                dataset_valid,
+            );
+            let model = config.sequence.init::<B>(n_classes, config.multi_label);
+            fit(
+                artifact_dir,
+                &config,
+                device,
+                checkpoint,
+                model,
+                dataloader_train,
+                dataloader_valid,
+            )
+        }
+        ModelKind::Fusion => {
+            let (dataloader_train, dataloader_valid) = dataloaders(
+                &config,
+                FusionBatcher::new(song_batcher(&device), sequence_batcher(&device)),
+                FusionBatcher::new(song_batcher_valid(&device), sequence_batcher_valid(&device)),
+                dataset_train,
+                dataset_valid,
+            );
+            let model = config.fusion.init::<B>(
+                n_classes,
+                tokenizer.vocab_size(),
+                config.fusion_branches(),
+                config.multi_label,
+            )?;
+            fit(
+                artifact_dir,
+                &config,
+                device,
+                checkpoint,
+                model,
+                dataloader_train,
+                dataloader_valid,
+            )
+        }
+    }
+}
+
+/// Trains the fusion model once for every combination of its enabled branches and prints how each
+/// of them scores on the validation split.
+pub fn train_ablation<B: AutodiffBackend>(
+    artifact_dir: &ArtifactDir,
+    config: TrainingConfig,
+    device: B::Device,
+) -> Result<Vec<(String, ValidationReport)>> {
+    let mut reports = Vec::new();
+    for fusion in config.fusion.ablations() {
+        let name = fusion.name();
+        println!("Training ablation {name}");
+        let run_config = config
+            .clone()
+            .with_model(ModelKind::Fusion)
+            .with_fusion(fusion);
+        let report = train::<B>(
+            &artifact_dir.child("ablation").child(&name),
+            run_config,
+            device.clone(),
+            false,
+        )
+        .with_context(|| format!("Error in training ablation {name}"))?;
+        reports.push((name, report));
+    }
+
+    println!("{:<24} {:>10} {:>10}", "Branches", "Accuracy", "Loss");
+    reports.iter().for_each(|(name, report)| {
+        println!(
+            "{:<24} {:>9.2}% {:>10.4}",
+            name,
+            report.accuracy * 100.0,
+            report.loss
+        )
+    });
+    Ok(reports)
+}
+
+// Both splits go through the same kind of batcher, the validation one on the inner backend
+fn dataloaders<TB, VB, TI, VI>(
+    config: &TrainingConfig,
+    batcher_train: TB,
+    batcher_valid: VB,
+    dataset_train: SublistDataset,
+    dataset_valid: SublistDataset,
+) -> (Arc<dyn DataLoader<TI>>, Arc<dyn DataLoader<VI>>)
+where
+    TB: Batcher<TrackClassificationItem, TI> + 'static,
+    VB: Batcher<TrackClassificationItem, VI> + 'static,
+    TI: Send + Clone + Debug + 'static,
+    VI: Send + Clone + Debug + 'static,
+{
+    let dataloader_train = DataLoaderBuilder::new(batcher_train)
+        .batch_size(config.batch_size)
+        .shuffle(config.seed)
+        .num_workers(config.num_workers)
+        .build(dataset_train);
+    let dataloader_valid = DataLoaderBuilder::new(batcher_valid)
+        .batch_size(config.batch_size)
+        .num_workers(config.num_workers)
+        .build(dataset_valid);
+    (dataloader_train, dataloader_valid)
+}
+
+// Runs the learner for any of the classifiers and saves the trained model to the artifact directory
+fn fit<B, M, TI, VI>(
+    artifact_dir: &ArtifactDir,
+    config: &TrainingConfig,
+    device: B::Device,
+    checkpoint: Option<usize>,
+    model: M,
+    dataloader_train: Arc<dyn DataLoader<TI>>,
+    dataloader_valid: Arc<dyn DataLoader<VI>>,
+) -> Result<ValidationReport>
+where
+    B: AutodiffBackend,
+    M: AutodiffModule<B> + TrainStep<TI, ClassificationOutput<B>> + Display + 'static,
+    M::InnerModule: ValidStep<VI, ClassificationOutput<B::InnerBackend>>,
+    TI: Send + 'static,
+    VI: LabelSets<B::InnerBackend> + Send + 'static,
+{
+    let mut builder = LearnerBuilder::new(artifact_dir.path().to_str().unwrap_or("."))
+        .metric_train_numeric(LossMetric::new())
+        .metric_valid_numeric(LossMetric::new())
+        .with_file_checkpointer(CompactRecorder::new())
+        .with_checkpointing_strategy(EveryNEpochs::new(
+            config.checkpoint_every,
+            config.keep_checkpoints,
+        ))
+        .devices(vec![device])
+        .num_epochs(config.num_epochs);
+    // The accuracy metric only checks the top class against the first label, multi-label models
+    // are scored on every class by `evaluate` once training is done
+    if !config.multi_label {
+        builder = builder
+            .metric_train_numeric(AccuracyMetric::new())
+            .metric_valid_numeric(AccuracyMetric::new());
+    }
+    if let Some(epoch) = checkpoint {
+        builder = builder.checkpoint(epoch);
+    }
+    let learner = builder.build(model, config.optimizer.init(), config.learning_rate);
+
+    let model_trained = learner.fit(dataloader_train, dataloader_valid.clone());
+    let report = evaluate::<B::InnerBackend, _, _>(
+        &model_trained.valid(),
+        dataloader_valid,
+        config.multi_label,
+    );
+    println!(
+        "Validation accuracy: {:.2}%, loss: {:.4}",
+        report.accuracy * 100.0,
+        report.loss
+    );
+
+    model_trained
+        .save_file(artifact_dir.model_path(), &CompactRecorder::new())
+        .context("Error in saving trained model")?;
+    artifact_dir.save_metrics(&report)?;
+    Ok(report)
+}
+
+fn evaluate<B, M, VI>(
+    model: &M,
+    dataloader: Arc<dyn DataLoader<VI>>,
+    multi_label: bool,
+) -> ValidationReport
+where
+    B: burn::tensor::backend::Backend,
+    M: ValidStep<VI, ClassificationOutput<B>>,
+    VI: LabelSets<B>,
+{
+    let (mut correct, mut loss, mut total) = (0.0, 0.0, 0);
+    for batch in dataloader.iter() {
+        let label_sets = batch.label_sets();
+        let output = model.step(batch);
+        let [batch_size, _] = output.output.dims();
+        let right = match multi_label {
+            // A logit above 0 is a probability above 0.5, a track only counts as right when no
+            // class is predicted wrong
+            true => (output.output.greater_elem(0.0).float() - label_sets)
+                .abs()
+                .sum_dim(1)
+                .lower_elem(0.5)
+                .int()
+                .sum(),
+            false => output
+                .output
+                .argmax(1)
+                .reshape([batch_size])
+                .equal(output.targets)
+                .int()
+                .sum(),
+        };
+        correct += right.into_scalar().elem::<f64>();
+        loss += output.loss.into_scalar().elem::<f64>() * batch_size as f64;
+        total += batch_size;
+    }
+
+    let total = total.max(1) as f64;
+    ValidationReport {
+        accuracy: correct / total,
+        loss: loss / total,
+    }
+}
+
+/// Saves a checkpoint every `every` epochs and deletes the ones older than the last `keep`.
+struct EveryNEpochs {
+    every: usize,
+    keep: usize,
+}
+
+impl EveryNEpochs {
+    fn new(every: usize, keep: usize) -> Self {
+        Self {
+            every: every.max(1),
+            keep: keep.max(1),
+        }
+    }
+}
+
+impl CheckpointingStrategy for EveryNEpochs {
+    fn checkpointing(
+        &mut self,
+        epoch: usize,
+        _store: &EventStoreClient,
+    ) -> Vec<CheckpointingAction> {
+        if epoch % self.every != 0 {
+            return vec![];
+        }
+        // Checkpoints are only ever saved at multiples of `every`, so the one that falls out of
+        // the window is known without tracking what was saved before a resume
+        match epoch.checked_sub(self.keep * self.every) {
+            Some(expired) if expired > 0 => vec![
+                CheckpointingAction::Delete(expired),
+                CheckpointingAction::Save,
+            ],
+            _ => vec![CheckpointingAction::Save],
+        }
+    }
+}
@@ -0,0 +1,611 @@
+use std::collections::HashMap;
+use std::io::Stdout;
+
+use anyhow::{Context, Result};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    Frame, Terminal,
+};
+
+use crate::data_structs::TrimmedTrack;
+use crate::label_store::{LabelSource, LabelStore};
+use crate::labels::{self, Guidance};
+use crate::session::LabelSession;
+use crate::thresholds::UNSORTED;
+
+// Keys that toggle the sublists in order, letters used by commands (a, q, s, u) are left out
+const HOTKEYS: &str = "123456789bcdefghijklmnoprtvwxyz";
+
+const HISTORY_LENGTH: usize = 8;
+
+/// Puts the terminal in full-screen raw mode and restores it when dropped, also on errors and
+/// panics, so the shell is never left unusable.
+struct TerminalGuard {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+}
+
+impl TerminalGuard {
+    fn new() -> Result<Self> {
+        enable_raw_mode().context("Error in enabling raw terminal mode")?;
+        execute!(std::io::stdout(), EnterAlternateScreen)
+            .context("Error in entering alternate screen")?;
+        let terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))
+            .context("Error in creating terminal")?;
+        Ok(Self { terminal })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(std::io::stdout(), LeaveAlternateScreen);
+    }
+}
+
+// Blocks until a key is pressed, ignoring releases and resizes
+fn next_key() -> Result<KeyCode> {
+    loop {
+        if let Event::Key(key) = event::read().context("Error in reading terminal input")? {
+            if key.kind == KeyEventKind::Press {
+                return Ok(key.code);
+            }
+        }
+    }
+}
+
+fn hotkey_class(key: char, sublists_len: usize) -> Option<u32> {
+    HOTKEYS
+        .chars()
+        .take(sublists_len)
+        .position(|x| x == key)
+        .map(|x| x as u32 + 1)
+}
+
+fn toggle(selection: &mut Vec<u32>, class: u32) {
+    match selection.iter().position(|x| *x == class) {
+        Some(i) => {
+            selection.remove(i);
+        }
+        None => {
+            selection.push(class);
+            selection.sort();
+        }
+    }
+}
+
+// The sublists with their hotkeys, marking the selected ones and the model's guess
+fn sublist_items<'a>(sublists: &'a [String], selected: &[u32], guess: &[u32]) -> Vec<ListItem<'a>> {
+    sublists
+        .iter()
+        .zip(HOTKEYS.chars())
+        .enumerate()
+        .map(|(i, (name, key))| {
+            let class = i as u32 + 1;
+            let mark = if selected.contains(&class) {
+                "[x]"
+            } else {
+                "[ ]"
+            };
+            let hint = if guess.contains(&class) {
+                "  (guess)"
+            } else {
+                ""
+            };
+            ListItem::new(format!("{key} {mark} {name}{hint}"))
+        })
+        .collect()
+}
+
+fn bordered(title: &str) -> Block<'_> {
+    Block::default().borders(Borders::ALL).title(title)
+}
+
+enum Mode {
+    Labelling,
+    Search {
+        query: String,
+        matches: Vec<usize>,
+        state: ListState,
+    },
+}
+
+struct LabelView<'a> {
+    sublists: &'a [String],
+    motherlist: &'a [TrimmedTrack],
+    labels: Vec<Vec<u32>>,
+    guesses: Vec<Vec<u32>>,
+    session: LabelSession,
+    index_of: HashMap<&'a str, usize>,
+    // Sublists picked for the current track, not yet confirmed
+    selected: Vec<u32>,
+    // Motherlist indices labelled in this run, latest last
+    recent: Vec<usize>,
+    mode: Mode,
+    message: String,
+}
+
+impl<'a> LabelView<'a> {
+    fn current(&self) -> Option<usize> {
+        self.session.current().map(|x| self.index_of[x.as_str()])
+    }
+
+    // Starts the selection from the current labels of the track, so relabelling only needs the
+    // changes
+    fn reset_selection(&mut self) {
+        self.selected = self
+            .current()
+            .map(|x| self.labels[x].clone())
+            .unwrap_or_default();
+    }
+
+    fn confirm(&mut self, index: usize) {
+        let labels = match self.selected.is_empty() {
+            true => self.guesses[index].clone(),
+            false => self.selected.clone(),
+        };
+        if labels.is_empty() {
+            self.message = "Pick at least one sublist, or s to skip".to_string();
+            return;
+        }
+        let previous = std::mem::replace(&mut self.labels[index], labels.clone());
+        self.session.label(previous, labels);
+        self.recent.push(index);
+        self.message.clear();
+        self.reset_selection();
+    }
+
+    fn undo(&mut self) {
+        match self.session.undo() {
+            Some((track_id, previous)) => {
+                let index = self.index_of[track_id.as_str()];
+                self.labels[index] = previous;
+                if let Some(i) = self.recent.iter().rposition(|x| *x == index) {
+                    self.recent.remove(i);
+                }
+                self.message = "Undid the last label".to_string();
+            }
+            None => self.message = "Nothing to undo".to_string(),
+        }
+        self.reset_selection();
+    }
+
+    fn search_matches(&self, query: &str) -> Vec<usize> {
+        let query = query.to_lowercase();
+        self.motherlist
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| {
+                x.track_name.to_lowercase().contains(&query)
+                    || x.artists.iter().any(|a| a.to_lowercase().contains(&query))
+            })
+            .map(|(i, _)| i)
+            .take(50)
+            .collect()
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(10),
+                Constraint::Min(6),
+                Constraint::Length(HISTORY_LENGTH as u16 + 2),
+                Constraint::Length(1),
+            ])
+            .split(frame.size());
+        let middle = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+            .split(rows[1]);
+
+        self.draw_track(frame, rows[0]);
+        match &mut self.mode {
+            Mode::Labelling => {
+                let guess = self
+                    .current()
+                    .map(|x| self.guesses[x].clone())
+                    .unwrap_or_default();
+                frame.render_widget(
+                    List::new(sublist_items(self.sublists, &self.selected, &guess))
+                        .block(bordered("Sublists")),
+                    middle[0],
+                );
+            }
+            Mode::Search {
+                query,
+                matches,
+                state,
+            } => {
+                let items: Vec<ListItem> = matches
+                    .iter()
+                    .map(|x| {
+                        let track = &self.motherlist[*x];
+                        ListItem::new(format!(
+                            "{} — {}  {:?}",
+                            track.track_name,
+                            track.artists.join(", "),
+                            labels::sublist_names(self.sublists, &self.labels[*x])
+                        ))
+                    })
+                    .collect();
+                frame.render_stateful_widget(
+                    List::new(items)
+                        .block(bordered(&format!("Search: {query}")))
+                        .highlight_style(Style::default().add_modifier(Modifier::REVERSED)),
+                    middle[0],
+                    state,
+                );
+            }
+        }
+        self.draw_counts(frame, middle[1]);
+        self.draw_history(frame, rows[2]);
+
+        let help = match self.mode {
+            Mode::Labelling => {
+                "keys toggle sublists · enter confirm/accept guess · s skip · u undo · / search · q quit"
+            }
+            Mode::Search { .. } => "type to search · ↑↓ pick · enter label it · esc back",
+        };
+        let footer = match self.message.is_empty() {
+            true => help.to_string(),
+            false => format!("{} · {help}", self.message),
+        };
+        frame.render_widget(Paragraph::new(footer), rows[3]);
+    }
+
+    fn draw_track(&self, frame: &mut Frame, area: Rect) {
+        let Some(index) = self.current() else {
+            frame.render_widget(
+                Paragraph::new("No more tracks in motherlist").block(bordered("Track")),
+                area,
+            );
+            return;
+        };
+        let track = &self.motherlist[index];
+        let mut lines = vec![
+            Line::styled(
+                track.track_name.clone(),
+                Style::default().add_modifier(Modifier::BOLD),
+            ),
+            Line::from(format!("Artists:  {}", track.artists.join(", "))),
+            Line::from(format!(
+                "Album:    {} ({})",
+                track.album_name,
+                track.release_date()
+            )),
+        ];
+        lines.extend(track.highlights().chunks(3).map(|x| {
+            Line::from(
+                x.iter()
+                    .map(|(name, value)| format!("{name}: {value:.2}"))
+                    .collect::<Vec<_>>()
+                    .join("   "),
+            )
+        }));
+        if !self.labels[index].is_empty() {
+            lines.push(Line::from(format!(
+                "Labelled: {}",
+                labels::sublist_names(self.sublists, &self.labels[index])
+                    .iter()
+                    .map(|x| x.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+        let title = format!("Track ({} left)", self.session.remaining());
+        frame.render_widget(Paragraph::new(lines).block(bordered(&title)), area);
+    }
+
+    fn draw_counts(&self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .sublists
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let count = self
+                    .labels
+                    .iter()
+                    .filter(|x| x.contains(&(i as u32 + 1)))
+                    .count();
+                ListItem::new(format!("{count:>5} {name}"))
+            })
+            .collect();
+        frame.render_widget(List::new(items).block(bordered("Labels per sublist")), area);
+    }
+
+    fn draw_history(&self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .recent
+            .iter()
+            .rev()
+            .take(HISTORY_LENGTH)
+            .map(|x| {
+                ListItem::new(format!(
+                    "{} → {}",
+                    self.motherlist[*x].track_name,
+                    labels::sublist_names(self.sublists, &self.labels[*x])
+                        .iter()
+                        .map(|x| x.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            })
+            .collect();
+        frame.render_widget(List::new(items).block(bordered("Recent")), area);
+    }
+
+    // Returns false once the user quits
+    fn handle(&mut self, key: KeyCode) -> bool {
+        match &mut self.mode {
+            Mode::Search {
+                query,
+                matches,
+                state,
+            } => match key {
+                KeyCode::Esc => self.mode = Mode::Labelling,
+                KeyCode::Up => state.select(state.selected().map(|x| x.saturating_sub(1))),
+                KeyCode::Down => state.select(
+                    state
+                        .selected()
+                        .map(|x| (x + 1).min(matches.len().saturating_sub(1))),
+                ),
+                KeyCode::Enter => {
+                    if let Some(found) = state.selected().and_then(|x| matches.get(x)) {
+                        let track_id = self.motherlist[*found].track_id.clone();
+                        self.session.jump_to(&track_id);
+                    }
+                    self.mode = Mode::Labelling;
+                    self.reset_selection();
+                }
+                KeyCode::Backspace | KeyCode::Char(_) => {
+                    match key {
+                        KeyCode::Char(c) => query.push(c),
+                        _ => {
+                            query.pop();
+                        }
+                    }
+                    let query = query.clone();
+                    let found = self.search_matches(&query);
+                    if let Mode::Search { matches, state, .. } = &mut self.mode {
+                        state.select((!found.is_empty()).then_some(0));
+                        *matches = found;
+                    }
+                }
+                _ => {}
+            },
+            Mode::Labelling => {
+                let Some(index) = self.current() else {
+                    return !matches!(key, KeyCode::Char('q') | KeyCode::Esc);
+                };
+                match key {
+                    KeyCode::Char('q') | KeyCode::Esc => return false,
+                    KeyCode::Char('s') => {
+                        self.session.skip();
+                        self.reset_selection();
+                    }
+                    KeyCode::Char('u') => self.undo(),
+                    KeyCode::Char('/') => {
+                        self.mode = Mode::Search {
+                            query: String::new(),
+                            matches: vec![],
+                            state: ListState::default(),
+                        }
+                    }
+                    KeyCode::Enter => self.confirm(index),
+                    KeyCode::Char(c) => match hotkey_class(c, self.sublists.len()) {
+                        Some(class) => toggle(&mut self.selected, class),
+                        None => self.message = format!("{c} isn't a sublist or command"),
+                    },
+                    _ => {}
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Full-screen version of [`labels::get_labels`], with the same sessions, ordering and guesses.
+/// Only the first sublists that have a hotkey can be picked.
+pub fn label_tracks(
+    sublists: &[String],
+    motherlist: &[TrimmedTrack],
+    mut labels: Vec<Vec<u32>>,
+    guidance: Option<Guidance>,
+) -> Result<Vec<Vec<u32>>> {
+    let (session, guesses) =
+        labels::start_session(sublists, motherlist, &mut labels, guidance, true)?;
+    let mut view = LabelView {
+        sublists,
+        motherlist,
+        labels,
+        guesses,
+        session,
+        index_of: labels::index_of(motherlist),
+        selected: vec![],
+        recent: vec![],
+        mode: Mode::Labelling,
+        message: String::new(),
+    };
+    view.reset_selection();
+
+    let mut guard = TerminalGuard::new()?;
+    loop {
+        guard
+            .terminal
+            .draw(|frame| view.draw(frame))
+            .context("Error in drawing terminal")?;
+        if !view.handle(next_key()?) {
+            break;
+        }
+        view.session.save()?;
+    }
+    drop(guard);
+
+    match view.session.remaining() {
+        0 => view.session.discard()?,
+        _ => view.session.save()?,
+    }
+    Ok(view.labels)
+}
+
+/// A predicted track waiting to be confirmed by the user.
+#[derive(Debug, Clone)]
+pub struct ReviewItem {
+    pub track_id: String,
+    pub track_name: String,
+    pub artists: Vec<String>,
+    pub sublists: Vec<String>,
+    pub confidence: f32,
+}
+
+/// Predictions of tracks that don't have a manual label yet, least confident first.
+pub fn pending_predictions(store: &LabelStore, tracks: &[TrimmedTrack]) -> Vec<ReviewItem> {
+    let mut items: Vec<ReviewItem> = tracks
+        .iter()
+        .filter(|x| store.get(&x.track_id, LabelSource::Manual).is_none())
+        .filter_map(|track| {
+            let entry = store.get(&track.track_id, LabelSource::Predicted)?;
+            Some(ReviewItem {
+                track_id: track.track_id.clone(),
+                track_name: track.track_name.clone(),
+                artists: track.artists.clone(),
+                sublists: entry.sublists.clone(),
+                confidence: entry
+                    .probabilities
+                    .as_ref()
+                    .map(|x| x.values().cloned().fold(0.0, f32::max))
+                    .unwrap_or(0.0),
+            })
+        })
+        .collect();
+    items.sort_by(|a, b| a.confidence.total_cmp(&b.confidence));
+    items
+}
+
+/// Lists predictions so each can be accepted as is or corrected. Returns the manual label of
+/// every reviewed track by track id.
+pub fn review(items: &[ReviewItem], sublists: &[String]) -> Result<Vec<(String, Vec<String>)>> {
+    let mut decisions: Vec<(String, Vec<String>)> = vec![];
+    let mut state = ListState::default();
+    state.select((!items.is_empty()).then_some(0));
+    // Sublists picked as a correction for the selected prediction
+    let mut selected: Vec<u32> = vec![];
+
+    let mut guard = TerminalGuard::new()?;
+    loop {
+        guard
+            .terminal
+            .draw(|frame| {
+                let rows = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Min(5), Constraint::Length(8), Constraint::Length(1)])
+                    .split(frame.size());
+                let rows_items: Vec<ListItem> = items
+                    .iter()
+                    .map(|x| {
+                        let decided = decisions.iter().find(|(id, _)| *id == x.track_id);
+                        let status = match decided {
+                            Some((_, labels)) => format!("✓ {}", labels.join(", ")),
+                            None => x.sublists.join(", "),
+                        };
+                        ListItem::new(format!(
+                            "{:>5.1}%  {} — {}  →  {}",
+                            x.confidence * 100.0,
+                            x.track_name,
+                            x.artists.join(", "),
+                            status
+                        ))
+                    })
+                    .collect();
+                frame.render_stateful_widget(
+                    List::new(rows_items)
+                        .block(bordered("Predictions, least confident first"))
+                        .highlight_style(Style::default().add_modifier(Modifier::REVERSED)),
+                    rows[0],
+                    &mut state,
+                );
+                frame.render_widget(
+                    List::new(sublist_items(sublists, &selected, &[]))
+                        .block(bordered("Correct to")),
+                    rows[1],
+                );
+                frame.render_widget(
+                    Paragraph::new(
+                        "↑↓ move · a accept prediction · keys pick sublists, enter to correct · q done",
+                    ),
+                    rows[2],
+                );
+            })
+            .context("Error in drawing terminal")?;
+
+        let Some(current) = state.selected().and_then(|x| items.get(x)) else {
+            if matches!(next_key()?, KeyCode::Char('q') | KeyCode::Esc) {
+                break;
+            }
+            continue;
+        };
+        let mut decide = |labels: Vec<String>| {
+            decisions.retain(|(id, _)| *id != current.track_id);
+            decisions.push((current.track_id.clone(), labels));
+        };
+        match next_key()? {
+            KeyCode::Char('q') | KeyCode::Esc => break,
+            KeyCode::Up => state.select(state.selected().map(|x| x.saturating_sub(1))),
+            KeyCode::Down => state.select(state.selected().map(|x| (x + 1).min(items.len() - 1))),
+            // Accepting an unsorted prediction says nothing about where the track belongs
+            KeyCode::Char('a') if !current.sublists.iter().any(|x| x == UNSORTED) => {
+                decide(current.sublists.clone());
+                state.select(state.selected().map(|x| (x + 1).min(items.len() - 1)));
+            }
+            KeyCode::Enter if !selected.is_empty() => {
+                decide(
+                    labels::sublist_names(sublists, &selected)
+                        .into_iter()
+                        .cloned()
+                        .collect(),
+                );
+                selected.clear();
+                state.select(state.selected().map(|x| (x + 1).min(items.len() - 1)));
+            }
+            KeyCode::Char(c) => {
+                if let Some(class) = hotkey_class(c, sublists.len()) {
+                    toggle(&mut selected, class);
+                }
+            }
+            _ => {}
+        }
+    }
+    drop(guard);
+    Ok(decisions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hotkeys_leave_out_command_keys() {
+        assert!(!HOTKEYS.chars().any(|x| "aqsu".contains(x)));
+        let mut keys: Vec<char> = HOTKEYS.chars().collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), HOTKEYS.chars().count());
+    }
+
+    #[test]
+    fn hotkeys_only_pick_existing_sublists() {
+        assert_eq!(hotkey_class('1', 3), Some(1));
+        assert_eq!(hotkey_class('b', 10), Some(10));
+        assert_eq!(hotkey_class('b', 9), None);
+        assert_eq!(hotkey_class('a', 20), None);
+    }
+}